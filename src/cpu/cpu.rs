@@ -1,14 +1,63 @@
-use crate::cpu::{Flag, Opcode, Regs};
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::cpu::{Condition, Flag, Opcode, Regs};
 use crate::dbg::log;
-use crate::mem::{Mmu};
+use crate::mem::{Interrupt, Mmu};
+use crate::sys::state::{StateReader, StateWriter};
+
+/// Hook invoked around the execution of an instruction, with the address
+/// of the instruction and its decoded opcode.
+pub type StepHook = Box<dyn FnMut(u16, &Opcode)>;
+
+// Handler executing an opcode; returns the number of cycles it took.
+type OpcodeHandler = fn(&mut Cpu, &mut Mmu, &Opcode) -> usize;
+
+// Dispatch tables of the handlers, indexed by the opcode byte.  They are
+// built at compile time; opcodes without a handler (i.e., |None|) aren't
+// implemented.
+static DISPATCH_UN: [Option<OpcodeHandler>; 256] = Cpu::_build_dispatch_un();
+static DISPATCH_CB: [Option<OpcodeHandler>; 256] = Cpu::_build_dispatch_cb();
+
+/// Enumerates the errors which can happen when stepping the CPU.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CpuError {
+    /// The opcode |byte| at |pc| is either illegal, or not implemented.
+    UnknownOpcode { cb: bool, byte: u8, pc: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { cb, byte, pc } => write!(f,
+                "unknown {} opcode 0x{:02x} at pc=0x{:04x}",
+                if *cb { "cb-prefixed" } else { "un-prefixed" }, byte, pc),
+        }
+    }
+}
+
+impl error::Error for CpuError {}
 
 #[derive(Default)]
 /// Represents the LR35902 CPU (GameBoy's CPU).
 pub struct Cpu {
     is_halted: bool,
+    halt_bug: bool,
+    is_stopped: bool,
+    is_locked: bool,
+    is_strict: bool,
+    ime: bool,
+    ime_delay: u8,
     next_opcode_is_cb: bool,
     curr_opcode: Option<&'static Opcode>,
     regs: Regs,
+    pre_step_hook: Option<StepHook>,
+    post_step_hook: Option<StepHook>,
+    storm_threshold: Option<u32>,
+    storm_return_pc: u16,
+    storm_nservices: u32,
+    storm_hit: bool,
 }
 
 impl Cpu {
@@ -16,39 +65,298 @@ impl Cpu {
     pub fn new() -> Cpu {
         return Cpu {
             is_halted: false,
+            halt_bug: false,
+            is_stopped: false,
+            is_locked: false,
+            is_strict: true,
+            ime: false,
+            ime_delay: 0,
             next_opcode_is_cb: false,
             curr_opcode: None,
             regs: Regs::default(),
+            pre_step_hook: None,
+            post_step_hook: None,
+            storm_threshold: None,
+            storm_return_pc: 0x0000,
+            storm_nservices: 0,
+            storm_hit: false,
         };
     }
 
-    /// Steps the CPU through a fetch/decode/execute cycle.
-    pub fn step(&mut self, mmu: &mut Mmu) -> usize {
-        // The CPU can halt upon executing the HALT instruction,
-        // or decoding a unknown opcode. In which case, the CPU
-        // will not make further progress and just execute NOP
-        // instructions.
+    /// Sets the registers to the values the BIOS leaves them with, on a
+    /// DMG; execution resumes at the cartridge entry point (0x0100).
+    pub fn set_post_bios_regs(&mut self) {
+        self.regs.set_af(0x01b0);
+        self.regs.set_bc(0x0013);
+        self.regs.set_de(0x00d8);
+        self.regs.set_hl(0x014d);
+        self.regs.sp = 0xfffe;
+        self.regs.pc = 0x0100;
+    }
+
+    /// Serializes the registers and the internal state into |w|.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for flag in [self.is_halted, self.halt_bug, self.is_stopped, self.is_locked,
+                self.ime, self.next_opcode_is_cb].iter() {
+            w.write_bool(*flag);
+        }
+        w.write_u8(self.ime_delay);
+        for reg in [self.regs.a, self.regs.f, self.regs.b, self.regs.c,
+                self.regs.d, self.regs.e, self.regs.h, self.regs.l].iter() {
+            w.write_u8(*reg);
+        }
+        w.write_u16(self.regs.pc);
+        w.write_u16(self.regs.sp);
+    }
+
+    /// Restores the registers and the internal state from |r|.
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        for flag in [&mut self.is_halted, &mut self.halt_bug, &mut self.is_stopped,
+                &mut self.is_locked, &mut self.ime, &mut self.next_opcode_is_cb].iter_mut() {
+            **flag = r.read_bool()?;
+        }
+        self.ime_delay = r.read_u8()?;
+        for reg in [&mut self.regs.a, &mut self.regs.f, &mut self.regs.b, &mut self.regs.c,
+                &mut self.regs.d, &mut self.regs.e, &mut self.regs.h, &mut self.regs.l].iter_mut() {
+            **reg = r.read_u8()?;
+        }
+        // The lower nibble of F doesn't exist, even in a crafted state.
+        let f = self.regs.f;
+        self.regs.set_f(f);
+        self.regs.pc = r.read_u16()?;
+        self.regs.sp = r.read_u16()?;
+        self.curr_opcode = None;
+        return Ok(());
+    }
+
+    /// Returns the address of the next instruction to execute.
+    pub fn pc(&self) -> u16 {
+        self.regs.pc
+    }
+
+    /// Sets whether decoding an illegal opcode fails the step with a
+    /// |CpuError| (strict, the default) or locks up the CPU as it does on
+    /// hardware.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.is_strict = strict;
+    }
+
+    /// Sets a hook invoked after an instruction is decoded, but before it
+    /// is executed.
+    pub fn set_pre_step_hook(&mut self, hook: StepHook) {
+        self.pre_step_hook = Some(hook);
+    }
+
+    /// Sets a hook invoked after an instruction is executed.
+    pub fn set_post_step_hook(&mut self, hook: StepHook) {
+        self.post_step_hook = Some(hook);
+    }
+
+    /// Enables the detection of interrupt storms, for debugging hangs: a
+    /// warning is logged once |threshold| interrupts are serviced in a
+    /// row, without executing the instruction they interrupted.  Disabled
+    /// (|None|) by default.
+    pub fn set_storm_threshold(&mut self, threshold: Option<u32>) {
+        self.storm_threshold = threshold;
+        self.storm_nservices = 0;
+        self.storm_hit = false;
+    }
+
+    /// Returns |true| iff. an interrupt storm was detected since the last
+    /// call.
+    pub fn take_storm_hit(&mut self) -> bool {
+        std::mem::replace(&mut self.storm_hit, false)
+    }
+
+    /// Steps the CPU through a fetch/decode/execute cycle; returns the
+    /// number of cycles it took.  Fails if the opcode can't be executed,
+    /// in which case PC is left on it.
+    pub fn step(&mut self, mmu: &mut Mmu) -> Result<usize, CpuError> {
+        // The CPU can halt upon executing the HALT instruction.  In
+        // which case, the CPU will not make further progress until an
+        // interrupt is pending, regardless of IME.
         if self.is_halted {
-            return 1;
+            if mmu.pending_interrupt().is_none() {
+                return Ok(1);
+            }
+            self.is_halted = false;
+        }
+
+        // STOP behaves like HALT for now: a pending interrupt (e.g. the
+        // joypad one, on a button press) wakes the CPU up.
+        // TODO: switch speed on the CGB.
+        if self.is_stopped {
+            if mmu.pending_interrupt().is_none() {
+                return Ok(1);
+            }
+            self.is_stopped = false;
+        }
+
+        // Unlike HALT, nothing can get the CPU out of a lock up.
+        if self.is_locked {
+            return Ok(4);
+        }
+
+        // EI takes effect after the instruction following it.
+        if self.ime_delay > 0 {
+            self.ime_delay -= 1;
+            if self.ime_delay == 0 {
+                self._set_ime(true);
+            }
+        }
+
+        if let Some(int) = self._get_serviceable_interrupt(mmu) {
+            return Ok(self._service_interrupt(mmu, int));
         }
 
-        self.curr_opcode = Opcode::from(
-            self.next_opcode_is_cb, self._fetch_next_byte(mmu));
+        // The interrupted code made progress: this is not a storm.
+        if self.regs.pc == self.storm_return_pc {
+            self.storm_nservices = 0;
+        }
+
+        let pc = self.regs.pc;
+        let byte = self._fetch_next_byte(mmu);
+        if self.halt_bug {
+            // PC fails to increment: the byte will be fetched again.
+            self.halt_bug = false;
+            self.regs.pc = pc;
+        }
+        let is_cb = self.next_opcode_is_cb;
+        self.curr_opcode = Opcode::from(is_cb, byte);
         if self.curr_opcode.is_none() {
-            self._panic("got an invalid opcode");
+            if self.is_strict || !Opcode::is_illegal(is_cb, byte) {
+                self.regs.pc = pc;
+                return Err(CpuError::UnknownOpcode { cb: is_cb, byte, pc });
+            }
+
+            log::warn("cpu", "step", &format!(
+                "illegal opcode 0x{:02x} at pc=0x{:04x}; cpu locked up",
+                byte, pc));
+            self.is_locked = true;
+            return Ok(4);
+        }
+
+        if !Opcode::is_implemented(is_cb, byte) {
+            self.regs.pc = pc;
+            return Err(CpuError::UnknownOpcode { cb: is_cb, byte, pc });
+        }
+
+        let opcode = self.curr_opcode.unwrap();
+        if let Some(hook) = self.pre_step_hook.as_mut() {
+            hook(pc, opcode);
         }
 
         let res = if self.next_opcode_is_cb {
             self.next_opcode_is_cb = false;
-            self._run_opcode_cb(mmu, self.curr_opcode.unwrap())
+            self._run_opcode_cb(mmu, opcode)
         } else {
-            self._run_opcode_un(mmu, self.curr_opcode.unwrap())
+            self._run_opcode_un(mmu, opcode)
         };
 
-        log::info("cpu", "step", &format!("executed_opcode={}, regs={}",
-            self.curr_opcode.unwrap(), self.regs));
+        if let Some(hook) = self.post_step_hook.as_mut() {
+            hook(pc, opcode);
+        }
 
-        return res;
+        if log::is_enabled(log::Level::Trace, "cpu") {
+            log::trace("cpu", "step", &self.trace());
+        }
+
+        return Ok(res);
+    }
+
+    /// Returns a trace line describing the last executed opcode, and the
+    /// resulting registers.
+    pub fn trace(&self) -> String {
+        return match self.curr_opcode {
+            Some(opcode) => format!("executed_opcode={}, regs={}", opcode, self.regs),
+            None => format!("executed_opcode=none, regs={}", self.regs),
+        };
+    }
+
+    /// Returns the CPU state as a single line, following the format used by
+    /// BGB and GameBoy Doctor: registers, followed by the 4 bytes at PC.
+    pub fn dump_state(&self, mmu: &Mmu) -> String {
+        let pcmem = self._read_pcmem(mmu);
+        return format!("{} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self._format_regs(), pcmem[0], pcmem[1], pcmem[2], pcmem[3]);
+    }
+
+    /// Returns the CPU state as a single line, following the format of the
+    /// Blargg test ROM logs: registers, followed by the 4 bytes at PC.
+    pub fn dump_state_blargg(&self, mmu: &Mmu) -> String {
+        let pcmem = self._read_pcmem(mmu);
+        return format!("{} ({:02X} {:02X} {:02X} {:02X})",
+            self._format_regs(), pcmem[0], pcmem[1], pcmem[2], pcmem[3]);
+    }
+
+    // Returns the registers, formatted for the state dumps.
+    fn _format_regs(&self) -> String {
+        return format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} \
+             SP:{:04X} PC:{:04X}",
+            self.regs.a, self.regs.f, self.regs.b, self.regs.c,
+            self.regs.d, self.regs.e, self.regs.h, self.regs.l,
+            self.regs.sp, self.regs.pc);
+    }
+
+    // Returns the 4 bytes at PC.
+    fn _read_pcmem(&self, mmu: &Mmu) -> [u8; 4] {
+        let pc = self.regs.pc;
+        return [
+            mmu.read_byte(pc),
+            mmu.read_byte(u16::wrapping_add(pc, 1)),
+            mmu.read_byte(u16::wrapping_add(pc, 2)),
+            mmu.read_byte(u16::wrapping_add(pc, 3)),
+        ];
+    }
+
+    fn _set_ime(&mut self, ime: bool) {
+        if self.ime != ime && log::is_enabled(log::Level::Trace, "int") {
+            log::trace("int", "set_ime", &format!("ime={}", ime));
+        }
+        self.ime = ime;
+    }
+
+    // Returns the interrupt to service, if interrupts are enabled.
+    fn _get_serviceable_interrupt(&self, mmu: &Mmu) -> Option<Interrupt> {
+        if !self.ime {
+            return None;
+        }
+        return mmu.pending_interrupt();
+    }
+
+    // Services |int|: disables interrupts, and calls its handler.  This
+    // takes 20 cycles.
+    fn _service_interrupt(&mut self, mmu: &mut Mmu, int: Interrupt) -> usize {
+        mmu.ack_interrupt(int);
+        self._set_ime(false);
+        self._stack_push(mmu, self.regs.pc);
+        if let Some(threshold) = self.storm_threshold {
+            self._detect_storm(int, threshold);
+        }
+        self.regs.pc = int.vector();
+
+        if log::is_enabled(log::Level::Trace, "int") {
+            log::trace("int", "service", &format!("int={:?} vector=0x{:04x}",
+                int, int.vector()));
+        }
+
+        return 20;
+    }
+
+    // Counts the interrupts serviced in a row with the same return address;
+    // flags a storm once the count reaches |threshold|.
+    fn _detect_storm(&mut self, int: Interrupt, threshold: u32) {
+        if self.regs.pc != self.storm_return_pc {
+            self.storm_return_pc = self.regs.pc;
+            self.storm_nservices = 0;
+        }
+        self.storm_nservices += 1;
+        if self.storm_nservices == threshold {
+            self.storm_hit = true;
+            log::warn("int", "storm", &format!("int={:?} serviced {} times in a row, pc=0x{:04x}",
+                int, threshold, self.regs.pc));
+        }
     }
 
     // Fetch the next byte from PC and increase PC.
@@ -67,158 +375,680 @@ impl Cpu {
 
     // Runs a cb-prefixed opcode.
     fn _run_opcode_cb(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
-        // Some instructions take a different number of cycles depending
-        // on whether memory accesses were successful, or branches taken.
-        // The dispatch code below will update the number of cycles if it
-        // is different from the 'default' path. As such, we will have the
-        // variable below mutable.
-        let mut ncycles = opcode.ncycles.0;
-
-        match (opcode.x(), opcode.y()) {
-            (0, 2) => { // RL r[z]
-                let n = self._alu_rl(self._get_r8_from_r(mmu, opcode.z()));
-                self._set_r8_from_r(mmu, opcode.z(), n);
-            },
-            (1, _) => { // BIT y, r[z]
-                let r = self._get_r8_from_r(mmu, opcode.z());
-                self.regs.set_flag(Flag::Z, (r & (1 << opcode.y())) == 0);
-                self.regs.set_flag(Flag::N, false);
-                self.regs.set_flag(Flag::H, true);
-            },
-            _ => {
-                self._panic("cb-prefixed opcode not implemented");
-            },
+        return match DISPATCH_CB[opcode.value as usize] {
+            Some(handler) => handler(self, mmu, opcode),
+            // |step| rejects the opcodes without a handler.
+            None => unreachable!("cb-prefixed opcode 0x{:02x} has no handler", opcode.value),
         };
-
-        return ncycles;
     }
 
     // Runs a un-prefixed opcode.
     fn _run_opcode_un(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
-        // Some instructions take a different number of cycles depending
-        // on whether memory accesses were successful, or branches taken.
-        // The dispatch code below will update the number of cycles if it
-        // is different from the 'default' path. As such, we will have the
-        // variable below mutable.
-        let mut ncycles = opcode.ncycles.0;
-
-        match (opcode.x(), opcode.y(), opcode.z(), opcode.p(), opcode.q()) {
-            (0, _, 1, _, 0) => { // LD rp[p], nn
-                let nn = self._fetch_next_word(mmu);
-                self._set_r16_from_rp(mmu, opcode.p(), nn);
-            },
-            (0, _, 2, 0, 1) => { // LD A,(BC)
-                self.regs.a = mmu.read_byte(self.regs.bc());
-            },
-            (0, _, 2, 1, 1) => { // LD A,(DE)
-                self.regs.a = mmu.read_byte(self.regs.de());
-            },
-            (0, _, 2, 2, 0) => { // LD (HL+), A
-                mmu.write_byte(self.regs.hl(), self.regs.a);
-                self.regs.inc_hl();
-            },
-            (0, _, 2, 3, 0) => { // LD (HL-), A
-                mmu.write_byte(self.regs.hl(), self.regs.a);
-                self.regs.dec_hl();
-            },
-            (0, _, 3, _, 0) => { // INC rp[p]
-                let nn = self._get_r16_from_rp(mmu, opcode.p());
-                let rp = u16::wrapping_add(nn, 1);
-                self._set_r16_from_rp(mmu, opcode.p(), rp);
-            },
-            (0, _, 4, _, _) => { // INC r[y]
-                let n = self._get_r8_from_r(mmu, opcode.y());
-                let r = u8::wrapping_add(n, 1);
-                self._set_r8_from_r(mmu, opcode.y(), r);
-                self.regs.set_flag(Flag::Z, r == 0);
-                self.regs.set_flag(Flag::N, false);
-                self.regs.set_flag(Flag::H, (n & 0x0f) == 0x0f);
-            },
-            (0, _, 5, _, _) => { // DEC r[y]
-                let n = self._get_r8_from_r(mmu, opcode.y());
-                let r = u8::wrapping_sub(n, 1);
-                self._set_r8_from_r(mmu, opcode.y(), r);
-                self.regs.set_flag(Flag::Z, r == 0);
-                self.regs.set_flag(Flag::N, true);
-                self.regs.set_flag(Flag::H, (n & 0x0f) == 0x00);
-            },
-            (0, _, 6, _, _) => { // LD r[y], n
-                let n = self._fetch_next_byte(mmu);
-                self._set_r8_from_r(mmu, opcode.y(), n);
-            },
-            (0, 2, 7, _, _) => { // RLA
-                let n = self._alu_rl(self.regs.a);
-                self._set_r8_from_r(mmu, opcode.z(), n);
-            },
-            (0, 3, 0, _, _) => { // JR d
-                let d8 = self._fetch_next_byte(mmu) as i8;
-                let pc = (self.regs.pc as i32).wrapping_add(d8 as i32) as u16;
-                self.regs.pc = pc;
-            },
-            (0, 4..=7, 0, _, _) => { // JR cc[y-4], d
-                let d8 = self._fetch_next_byte(mmu) as i8;
-                let pc = i32::wrapping_add(self.regs.pc as i32, d8 as i32) as u16;
-                if self._get_res_from_cc(opcode.y() - 4) {
-                    self.regs.pc = pc;
-                } else {
-                    ncycles = opcode.ncycles.1;
-                }
-            },
-            (1, 6, 6, _, _) => { // HALT
-                self.is_halted = true;
-            },
-            (1, _, _, _, _) => { // LD r[y], r[z]
-                let r = self._get_r8_from_r(mmu, opcode.z());
-                self._set_r8_from_r(mmu, opcode.y(), r);
+        return match DISPATCH_UN[opcode.value as usize] {
+            Some(handler) => handler(self, mmu, opcode),
+            None => unreachable!("un-prefixed opcode 0x{:02x} has no handler", opcode.value),
+        };
+    }
+
+    // Returns |true| iff. the opcode |value| has a handler.
+    pub(super) fn has_handler(is_cb: bool, value: u8) -> bool {
+        return if is_cb {
+            DISPATCH_CB[value as usize].is_some()
+        } else {
+            DISPATCH_UN[value as usize].is_some()
+        };
+    }
+
+    // Builds the dispatch table of the cb-prefixed opcodes.
+    const fn _build_dispatch_cb() -> [Option<OpcodeHandler>; 256] {
+        let mut table: [Option<OpcodeHandler>; 256] = [None; 256];
+        let mut value = 0;
+        while value < 256 {
+            table[value] = match value >> 6 {
+                0 => Some(Cpu::_op_rot_r as OpcodeHandler),
+                1 => Some(Cpu::_op_bit as OpcodeHandler),
+                2 => Some(Cpu::_op_res as OpcodeHandler),
+                _ => Some(Cpu::_op_set as OpcodeHandler),
+            };
+            value += 1;
+        }
+        return table;
+    }
+
+    // Builds the dispatch table of the un-prefixed opcodes, from the
+    // (x, y, z, p, q) decomposition of each opcode.  The first matching
+    // pattern wins (e.g., HALT over LD r[y], r[z]).
+    const fn _build_dispatch_un() -> [Option<OpcodeHandler>; 256] {
+        let mut table: [Option<OpcodeHandler>; 256] = [None; 256];
+        let mut value = 0;
+        while value < 256 {
+            let v = value as u8;
+            let (x, y, z) = (v >> 6, (v >> 3) & 0x07, v & 0x07);
+            let (p, q) = ((v >> 4) & 0x03, (v >> 3) & 0x01);
+
+            table[value] = match (x, y, z, p, q) {
+                (0, 0, 0, _, _) => Some(Cpu::_op_nop as OpcodeHandler),
+                (0, 1, 0, _, _) => Some(Cpu::_op_ld_nn_sp as OpcodeHandler),
+                (0, _, 1, _, 0) => Some(Cpu::_op_ld_rp_nn as OpcodeHandler),
+                (0, _, 1, _, 1) => Some(Cpu::_op_add_hl_rp as OpcodeHandler),
+                (0, _, 2, 0, 0) => Some(Cpu::_op_ld_bc_a as OpcodeHandler),
+                (0, _, 2, 1, 0) => Some(Cpu::_op_ld_de_a as OpcodeHandler),
+                (0, _, 2, 0, 1) => Some(Cpu::_op_ld_a_bc as OpcodeHandler),
+                (0, _, 2, 1, 1) => Some(Cpu::_op_ld_a_de as OpcodeHandler),
+                (0, _, 2, 2, 0) => Some(Cpu::_op_ld_hli_a as OpcodeHandler),
+                (0, _, 2, 3, 0) => Some(Cpu::_op_ld_hld_a as OpcodeHandler),
+                (0, _, 2, 2, 1) => Some(Cpu::_op_ld_a_hli as OpcodeHandler),
+                (0, _, 2, 3, 1) => Some(Cpu::_op_ld_a_hld as OpcodeHandler),
+                (0, _, 3, _, 0) => Some(Cpu::_op_inc_rp as OpcodeHandler),
+                (0, _, 3, _, 1) => Some(Cpu::_op_dec_rp as OpcodeHandler),
+                (0, _, 4, _, _) => Some(Cpu::_op_inc_r as OpcodeHandler),
+                (0, _, 5, _, _) => Some(Cpu::_op_dec_r as OpcodeHandler),
+                (0, _, 6, _, _) => Some(Cpu::_op_ld_r_n as OpcodeHandler),
+                (0, 0..=3, 7, _, _) => Some(Cpu::_op_rot_a as OpcodeHandler),
+                (0, 4, 7, _, _) => Some(Cpu::_op_daa as OpcodeHandler),
+                (0, 5, 7, _, _) => Some(Cpu::_op_cpl as OpcodeHandler),
+                (0, 6, 7, _, _) => Some(Cpu::_op_scf as OpcodeHandler),
+                (0, 7, 7, _, _) => Some(Cpu::_op_ccf as OpcodeHandler),
+                (0, 2, 0, _, _) => Some(Cpu::_op_stop as OpcodeHandler),
+                (0, 3, 0, _, _) => Some(Cpu::_op_jr as OpcodeHandler),
+                (0, 4..=7, 0, _, _) => Some(Cpu::_op_jr_cc as OpcodeHandler),
+                (1, 6, 6, _, _) => Some(Cpu::_op_halt as OpcodeHandler),
+                (1, _, _, _, _) => Some(Cpu::_op_ld_r_r as OpcodeHandler),
+                (2, _, _, _, _) => Some(Cpu::_op_alu_r as OpcodeHandler),
+                (3, 0..=3, 0, _, _) => Some(Cpu::_op_ret_cc as OpcodeHandler),
+                (3, _, 1, 0, 1) => Some(Cpu::_op_ret as OpcodeHandler),
+                (3, _, 1, 1, 1) => Some(Cpu::_op_reti as OpcodeHandler),
+                (3, _, 1, _, 0) => Some(Cpu::_op_pop as OpcodeHandler),
+                (3, _, 1, 2, 1) => Some(Cpu::_op_jp_hl as OpcodeHandler),
+                (3, _, 1, 3, 1) => Some(Cpu::_op_ld_sp_hl as OpcodeHandler),
+                (3, 0..=3, 2, _, _) => Some(Cpu::_op_jp_cc as OpcodeHandler),
+                (3, 0, 3, _, _) => Some(Cpu::_op_jp as OpcodeHandler),
+                (3, 0..=3, 4, _, _) => Some(Cpu::_op_call_cc as OpcodeHandler),
+                (3, 6, 3, _, _) => Some(Cpu::_op_di as OpcodeHandler),
+                (3, 7, 3, _, _) => Some(Cpu::_op_ei as OpcodeHandler),
+                (3, _, 5, 0, 1) => Some(Cpu::_op_call as OpcodeHandler),
+                (3, _, 5, _, 0) => Some(Cpu::_op_push as OpcodeHandler),
+                (3, 1, 3, _, _) => Some(Cpu::_op_prefix_cb as OpcodeHandler),
+                (3, _, 7, _, _) => Some(Cpu::_op_rst as OpcodeHandler),
+                (3, 4, 0, _, _) => Some(Cpu::_op_ldh_n_a as OpcodeHandler),
+                (3, 4, 2, _, _) => Some(Cpu::_op_ldh_c_a as OpcodeHandler),
+                (3, 5, 2, _, _) => Some(Cpu::_op_ld_nn_a as OpcodeHandler),
+                (3, 5, 0, _, _) => Some(Cpu::_op_add_sp as OpcodeHandler),
+                (3, 6, 0, _, _) => Some(Cpu::_op_ldh_a_n as OpcodeHandler),
+                (3, 7, 0, _, _) => Some(Cpu::_op_ld_hl_sp as OpcodeHandler),
+                (3, 6, 2, _, _) => Some(Cpu::_op_ldh_a_c as OpcodeHandler),
+                (3, 7, 2, _, _) => Some(Cpu::_op_ld_a_nn as OpcodeHandler),
+                (3, _, 6, _, _) => Some(Cpu::_op_alu_n as OpcodeHandler),
+                _ => None,
+            };
+            value += 1;
+        }
+        return table;
+    }
+
+    // Returns the number of cycles taken by |opcode| on its default path,
+    // i.e. with branches taken.
+    fn _get_ncycles(opcode: &Opcode) -> usize {
+        Cpu::_get_ncycles_from_r(opcode, Cpu::_is_r_operand_hl(opcode))
+    }
+
+    // rot[y] r[z]
+    fn _op_rot_r(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let r = self._get_r8_from_r(mmu, opcode.z());
+        let n = match opcode.y() {
+            0 => self._alu_rlc(r),
+            1 => self._alu_rrc(r),
+            2 => self._alu_rl(r),
+            3 => self._alu_rr(r),
+            4 => self._alu_sla(r),
+            5 => self._alu_sra(r),
+            6 => self._alu_swap(r),
+            7 => self._alu_srl(r),
+            _ => unreachable!("impossible <rot> index"),
+        };
+        self._set_r8_from_r(mmu, opcode.z(), n);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // BIT y, r[z]
+    fn _op_bit(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let r = self._get_r8_from_r(mmu, opcode.z());
+        self.regs.set_flags(Some((r & (1 << opcode.y())) == 0),
+            Some(false), Some(true), None);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // RES y, r[z]
+    fn _op_res(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let r = self._get_r8_from_r(mmu, opcode.z());
+        self._set_r8_from_r(mmu, opcode.z(), r & !(1 << opcode.y()));
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // SET y, r[z]
+    fn _op_set(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let r = self._get_r8_from_r(mmu, opcode.z());
+        self._set_r8_from_r(mmu, opcode.z(), r | (1 << opcode.y()));
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // NOP
+    fn _op_nop(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD (nn), SP
+    fn _op_ld_nn_sp(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._fetch_next_word(mmu);
+        mmu.write_word(nn, self.regs.sp);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD rp[p], nn
+    fn _op_ld_rp_nn(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._fetch_next_word(mmu);
+        self._set_r16_from_rp(opcode.p(), nn);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // ADD HL, rp[p]
+    fn _op_add_hl_rp(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._get_r16_from_rp(opcode.p());
+        self._alu_add16(nn);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD (BC), A
+    fn _op_ld_bc_a(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        mmu.write_byte(self.regs.bc(), self.regs.a);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD (DE), A
+    fn _op_ld_de_a(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        mmu.write_byte(self.regs.de(), self.regs.a);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD A, (BC)
+    fn _op_ld_a_bc(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.a = mmu.read_byte(self.regs.bc());
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD A, (DE)
+    fn _op_ld_a_de(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.a = mmu.read_byte(self.regs.de());
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD (HL+), A
+    fn _op_ld_hli_a(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        mmu.write_byte(self.regs.hl(), self.regs.a);
+        self.regs.inc_hl();
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD (HL-), A
+    fn _op_ld_hld_a(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        mmu.write_byte(self.regs.hl(), self.regs.a);
+        self.regs.dec_hl();
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD A, (HL+)
+    fn _op_ld_a_hli(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.a = mmu.read_byte(self.regs.hl());
+        self.regs.inc_hl();
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD A, (HL-)
+    fn _op_ld_a_hld(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.a = mmu.read_byte(self.regs.hl());
+        self.regs.dec_hl();
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // INC rp[p]
+    fn _op_inc_rp(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._get_r16_from_rp(opcode.p());
+        let rp = u16::wrapping_add(nn, 1);
+        self._set_r16_from_rp(opcode.p(), rp);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // DEC rp[p]
+    fn _op_dec_rp(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._get_r16_from_rp(opcode.p());
+        let rp = u16::wrapping_sub(nn, 1);
+        self._set_r16_from_rp(opcode.p(), rp);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // INC r[y]
+    fn _op_inc_r(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let n = self._get_r8_from_r(mmu, opcode.y());
+        let r = u8::wrapping_add(n, 1);
+        self._set_r8_from_r(mmu, opcode.y(), r);
+        self.regs.set_flags(Some(r == 0), Some(false),
+            Some((n & 0x0f) == 0x0f), None);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // DEC r[y]
+    fn _op_dec_r(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let n = self._get_r8_from_r(mmu, opcode.y());
+        let r = u8::wrapping_sub(n, 1);
+        self._set_r8_from_r(mmu, opcode.y(), r);
+        self.regs.set_flags(Some(r == 0), Some(true),
+            Some((n & 0x0f) == 0x00), None);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD r[y], n
+    fn _op_ld_r_n(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let n = self._fetch_next_byte(mmu);
+        self._set_r8_from_r(mmu, opcode.y(), n);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // RLCA, RRCA, RLA, RRA
+    fn _op_rot_a(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let a = self.regs.a;
+        self.regs.a = match opcode.y() {
+            0 => self._alu_rlc(a),
+            1 => self._alu_rrc(a),
+            2 => self._alu_rl(a),
+            3 => self._alu_rr(a),
+            _ => unreachable!("impossible <rot> index"),
+        };
+        // Unlike their CB-prefixed counterparts, these always clear the Z
+        // flag.
+        self.regs.set_flags(Some(false), None, None, None);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // DAA
+    fn _op_daa(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self._alu_daa();
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // CPL
+    fn _op_cpl(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.a = !self.regs.a;
+        self.regs.set_flags(None, Some(true), Some(true), None);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // SCF
+    fn _op_scf(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.set_flags(None, Some(false), Some(false), Some(true));
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // CCF
+    fn _op_ccf(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let c = self.regs.get_flag(Flag::C);
+        self.regs.set_flags(None, Some(false), Some(false), Some(!c));
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // STOP
+    fn _op_stop(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        // STOP is encoded as 0x10 0x00: skip the padding byte.
+        self._fetch_next_byte(mmu);
+        self.is_stopped = true;
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // JR d
+    fn _op_jr(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let d8 = self._fetch_next_byte(mmu) as i8;
+        let pc = (self.regs.pc as i32).wrapping_add(d8 as i32) as u16;
+        self.regs.pc = pc;
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // JR cc[y-4], d
+    fn _op_jr_cc(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let d8 = self._fetch_next_byte(mmu) as i8;
+        let pc = i32::wrapping_add(self.regs.pc as i32, d8 as i32) as u16;
+        if !self._get_res_from_cc(Condition::from_index(opcode.y() - 4)) {
+            return opcode.ncycles.1;
+        }
+        self.regs.pc = pc;
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // HALT
+    fn _op_halt(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        // With interrupts disabled and one already pending, the CPU doesn't
+        // halt, and fails to increment PC on the next fetch (i.e., the HALT
+        // bug).
+        if !self.ime && mmu.pending_interrupt().is_some() {
+            self.halt_bug = true;
+        } else {
+            self.is_halted = true;
+        }
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD r[y], r[z]
+    fn _op_ld_r_r(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let r = self._get_r8_from_r(mmu, opcode.z());
+        self._set_r8_from_r(mmu, opcode.y(), r);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // alu[y] r[z]
+    fn _op_alu_r(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let r = self._get_r8_from_r(mmu, opcode.z());
+        self._alu_from_y(opcode.y(), r);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // RET cc[y]
+    fn _op_ret_cc(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        if !self._get_res_from_cc(Condition::from_index(opcode.y())) {
+            return opcode.ncycles.1;
+        }
+        self.regs.pc = self._stack_pop(mmu);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // RET
+    fn _op_ret(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.pc = self._stack_pop(mmu);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // RETI
+    fn _op_reti(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.pc = self._stack_pop(mmu);
+        self._set_ime(true);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // POP rp2[p]
+    fn _op_pop(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._stack_pop(mmu);
+        self._set_r16_from_rp2(opcode.p(), nn);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // JP (HL)
+    fn _op_jp_hl(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.pc = self.regs.hl();
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD SP, HL
+    fn _op_ld_sp_hl(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.sp = self.regs.hl();
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // JP cc[y], nn
+    fn _op_jp_cc(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._fetch_next_word(mmu);
+        if !self._get_res_from_cc(Condition::from_index(opcode.y())) {
+            return opcode.ncycles.1;
+        }
+        self.regs.pc = nn;
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // JP nn
+    fn _op_jp(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.pc = self._fetch_next_word(mmu);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // CALL cc[y], nn
+    fn _op_call_cc(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._fetch_next_word(mmu);
+        if !self._get_res_from_cc(Condition::from_index(opcode.y())) {
+            return opcode.ncycles.1;
+        }
+        self._stack_push(mmu, self.regs.pc);
+        self.regs.pc = nn;
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // DI
+    fn _op_di(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.ime_delay = 0;
+        self._set_ime(false);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // EI
+    fn _op_ei(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.ime_delay = 2;
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // CALL nn
+    fn _op_call(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._fetch_next_word(mmu);
+        self._stack_push(mmu, self.regs.pc);
+        self.regs.pc = nn;
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // PUSH rp2[p]
+    fn _op_push(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._get_r16_from_rp2(opcode.p());
+        self._stack_push(mmu, nn);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // PREFIX CB
+    fn _op_prefix_cb(&mut self, _mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.next_opcode_is_cb = true;
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // RST y*8
+    fn _op_rst(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self._stack_push(mmu, self.regs.pc);
+        self.regs.pc = (opcode.y() as u16) * 8;
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD (0xff00 + n), A
+    fn _op_ldh_n_a(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let n = self._fetch_next_byte(mmu) as u16;
+        mmu.write_byte(0xff00 + n, self.regs.a);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD (0xff00 + C), A
+    fn _op_ldh_c_a(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        mmu.write_byte(0xff00 + (self.regs.c as u16), self.regs.a);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD (nn), A
+    fn _op_ld_nn_a(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._fetch_next_word(mmu);
+        mmu.write_byte(nn, self.regs.a);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // ADD SP, d
+    fn _op_add_sp(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let d8 = self._fetch_next_byte(mmu);
+        self.regs.sp = self._alu_add_sp(d8);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD A, (0xff00 + n)
+    fn _op_ldh_a_n(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let n = self._fetch_next_byte(mmu) as u16;
+        self.regs.a = mmu.read_byte(0xff00 + n);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD HL, SP + d
+    fn _op_ld_hl_sp(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let d8 = self._fetch_next_byte(mmu);
+        let nn = self._alu_add_sp(d8);
+        self.regs.set_hl(nn);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD A, (0xff00 + C)
+    fn _op_ldh_a_c(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        self.regs.a = mmu.read_byte(0xff00 + (self.regs.c as u16));
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // LD A, (nn)
+    fn _op_ld_a_nn(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let nn = self._fetch_next_word(mmu);
+        self.regs.a = mmu.read_byte(nn);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // alu[y] n
+    fn _op_alu_n(&mut self, mmu: &mut Mmu, opcode: &Opcode) -> usize {
+        let n = self._fetch_next_byte(mmu);
+        self._alu_from_y(opcode.y(), n);
+        return Cpu::_get_ncycles(opcode);
+    }
+
+    // Returns whether one of the r[] operands of |opcode| is (HL), i.e.
+    // index 6 of the r[] table.
+    fn _is_r_operand_hl(opcode: &Opcode) -> bool {
+        if opcode.is_cb {
+            return opcode.z() == 6;
+        }
+
+        return match (opcode.x(), opcode.z()) {
+            (0, 4..=6) => opcode.y() == 6, // INC/DEC r[y], LD r[y],n
+            (1, _) => opcode.y() == 6 || opcode.z() == 6, // LD r[y],r[z]
+            (2, _) => opcode.z() == 6, // alu[y] r[z]
+            _ => false,
+        };
+    }
+
+    // Returns the number of cycles taken by |opcode|, given whether its
+    // r[] operand resolved to (HL).  Each access to (HL) costs 4 extra
+    // cycles over the register form; read-modify-write instructions
+    // access memory twice.  Opcodes outside of the r-block use the
+    // default cycle count from the opcode table.
+    fn _get_ncycles_from_r(opcode: &Opcode, is_hl: bool) -> usize {
+        let (ncycles_r, ncycles_hl) = if opcode.is_cb {
+            match opcode.x() {
+                1 => (8, 12), // BIT y,r[z]: only reads (HL)
+                _ => (8, 16), // rot[y] r[z], RES/SET y,r[z]
             }
-            (2, 5, _, _, _) => { // XOR r[z]
-                let r = self._get_r8_from_r(mmu, opcode.z());
-                self._alu_xor(r);
-            },
-            (3, _, 1, 0, 1) => { // RET
-                self.regs.pc = self._stack_pop(mmu);
+        } else {
+            match (opcode.x(), opcode.z()) {
+                (0, 4..=5) => (4, 12), // INC/DEC r[y]
+                (0, 6) => (8, 12), // LD r[y],n
+                (1, _) if opcode.value != 0x76 => (4, 8), // LD r[y],r[z]
+                (2, _) => (4, 8), // alu[y] r[z]
+                _ => return opcode.ncycles.0,
             }
-            (3, _, 1, _, 0) => { // POP rp2[p]
-                let nn = self._stack_pop(mmu);
-                self._set_r16_from_rp2(mmu, opcode.p(), nn);
-            },
-            (3, _, 5, 0, 1) => { // CALL nn
-                let nn = self._fetch_next_word(mmu);
-                self._stack_push(mmu, self.regs.pc);
-                self.regs.pc = nn;
-            },
-            (3, _, 5, _, 0) => { // PUSH rp2[p]
-                let nn = self._get_r16_from_rp2(mmu, opcode.p());
-                self._stack_push(mmu, nn);
-            },
-            (3, 1, 3, _, _) => {
-                self.next_opcode_is_cb = true;
-            },
-            (3, 4, 0, _, _) => { // LD (0xff00 + n),A
-                let n = self._fetch_next_byte(mmu) as u16;
-                mmu.write_byte(0xff00 + n, self.regs.a);
-            },
-            (3, 4, 2, _, _) => { // LD (0xff00 + C),A
-                mmu.write_byte(0xff00 + (self.regs.c as u16), self.regs.a);
-            },
-            (3, 5, 2, _, _) => { // LD (nn), A
-                let nn = self._fetch_next_word(mmu);
-                mmu.write_byte(nn, self.regs.a);
-            },
-            (3, 6, 0, _, _) => { // LDH A, (n)
-                let n = self._fetch_next_byte(mmu) as u16;
-                self.regs.a = mmu.read_byte(0xff00 + n);
-            },
-            (3, 7, _, _, _) => { // CP d8
-                let n = self._fetch_next_byte(mmu);
-                self._alu_cp(n);
-            },
-            _ => {
-                self._panic("un-prefixed opcode not implemented");
-            },
         };
 
-        return ncycles;
+        return if is_hl { ncycles_hl } else { ncycles_r };
+    }
+
+    // Runs the alu[y] operation on A and |d8|.
+    fn _alu_from_y(&mut self, y: u8, d8: u8) {
+        match y {
+            0 => self._alu_add(d8),
+            1 => self._alu_adc(d8),
+            2 => self._alu_sub(d8, false),
+            3 => self._alu_sub(d8, true),
+            4 => self._alu_and(d8),
+            5 => self._alu_xor(d8),
+            6 => self._alu_or(d8),
+            7 => self._alu_cp(d8),
+            _ => unreachable!("impossible <alu> index"),
+        };
+    }
+
+    fn _alu_add(&mut self, d8: u8) {
+        let a = self.regs.a;
+        let r = a.wrapping_add(d8);
+        self.regs.a = r;
+
+        self.regs.set_flags(Some(r == 0), Some(false),
+            Some((a & 0x0f) + (d8 & 0x0f) > 0x0f),
+            Some((a as u16) + (d8 as u16) > 0xff));
+    }
+
+    fn _alu_adc(&mut self, d8: u8) {
+        let c = if self.regs.get_flag(Flag::C) { 1 } else { 0 };
+        let a = self.regs.a;
+        let r = a.wrapping_add(d8).wrapping_add(c);
+        self.regs.a = r;
+
+        self.regs.set_flags(Some(r == 0), Some(false),
+            Some((a & 0x0f) + (d8 & 0x0f) + c > 0x0f),
+            Some((a as u16) + (d8 as u16) + (c as u16) > 0xff));
+    }
+
+    fn _alu_add16(&mut self, d16: u16) {
+        let hl = self.regs.hl();
+        let r = hl.wrapping_add(d16);
+        self.regs.set_hl(r);
+
+        self.regs.set_flags(None, Some(false),
+            Some((hl & 0x0fff) + (d16 & 0x0fff) > 0x0fff),
+            Some((hl as u32) + (d16 as u32) > 0xffff));
+    }
+
+    // Returns SP plus the signed offset |d8|.  The H and C flags are
+    // computed from the unsigned addition of the low byte of SP and |d8|.
+    fn _alu_add_sp(&mut self, d8: u8) -> u16 {
+        let sp = self.regs.sp;
+        let r = sp.wrapping_add(d8 as i8 as u16);
+
+        self.regs.set_flags(Some(false), Some(false),
+            Some((sp & 0x000f) + (d8 as u16 & 0x000f) > 0x000f),
+            Some((sp & 0x00ff) + (d8 as u16) > 0x00ff));
+
+        return r;
+    }
+
+    fn _alu_and(&mut self, d8: u8) {
+        self.regs.a &= d8;
+        self.regs.set_flags(Some(self.regs.a == 0), Some(false), Some(true), Some(false));
+    }
+
+    // Adjusts A into a valid BCD number, after an addition or a
+    // subtraction of two BCD numbers.
+    fn _alu_daa(&mut self) {
+        let mut a = self.regs.a;
+        let mut c = self.regs.get_flag(Flag::C);
+        let h = self.regs.get_flag(Flag::H);
+
+        if !self.regs.get_flag(Flag::N) {
+            if c || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                c = true;
+            }
+            if h || (a & 0x0f) > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        } else {
+            if c {
+                a = a.wrapping_sub(0x60);
+            }
+            if h {
+                a = a.wrapping_sub(0x06);
+            }
+        }
+
+        self.regs.a = a;
+        self.regs.set_flags(Some(a == 0), None, Some(false), Some(c));
     }
 
     fn _alu_cp(&mut self, d8: u8) {
@@ -231,43 +1061,96 @@ impl Cpu {
     fn _alu_rl(&mut self, d8: u8) -> u8 {
         let c = ((d8 & 0x80) >> 7) == 0x01;
         let r = ((d8 << 1) + u8::from(self.regs.get_flag(Flag::C)));
-        self.regs.set_flag(Flag::Z, r == 0x00);
-        self.regs.set_flag(Flag::N, false);
-        self.regs.set_flag(Flag::C, c);
-        self.regs.set_flag(Flag::H, false);
+        self.regs.set_flags(Some(r == 0x00), Some(false), Some(false), Some(c));
+
+        return r;
+    }
+
+    fn _alu_rlc(&mut self, d8: u8) -> u8 {
+        let c = (d8 & 0x80) != 0;
+        let r = d8.rotate_left(1);
+        self.regs.set_flags(Some(r == 0x00), Some(false), Some(false), Some(c));
+
+        return r;
+    }
+
+    fn _alu_rr(&mut self, d8: u8) -> u8 {
+        let c = (d8 & 0x01) != 0;
+        let r = (d8 >> 1) | (u8::from(self.regs.get_flag(Flag::C)) << 7);
+        self.regs.set_flags(Some(r == 0x00), Some(false), Some(false), Some(c));
+
+        return r;
+    }
+
+    fn _alu_rrc(&mut self, d8: u8) -> u8 {
+        let c = (d8 & 0x01) != 0;
+        let r = d8.rotate_right(1);
+        self.regs.set_flags(Some(r == 0x00), Some(false), Some(false), Some(c));
+
+        return r;
+    }
+
+    fn _alu_sla(&mut self, d8: u8) -> u8 {
+        let c = (d8 & 0x80) != 0;
+        let r = d8 << 1;
+        self.regs.set_flags(Some(r == 0x00), Some(false), Some(false), Some(c));
+
+        return r;
+    }
+
+    fn _alu_sra(&mut self, d8: u8) -> u8 {
+        // The sign bit (bit 7) is preserved.
+        let c = (d8 & 0x01) != 0;
+        let r = (d8 >> 1) | (d8 & 0x80);
+        self.regs.set_flags(Some(r == 0x00), Some(false), Some(false), Some(c));
+
+        return r;
+    }
+
+    fn _alu_srl(&mut self, d8: u8) -> u8 {
+        let c = (d8 & 0x01) != 0;
+        let r = d8 >> 1;
+        self.regs.set_flags(Some(r == 0x00), Some(false), Some(false), Some(c));
+
+        return r;
+    }
+
+    fn _alu_swap(&mut self, d8: u8) -> u8 {
+        let r = d8.rotate_left(4);
+        self.regs.set_flags(Some(r == 0x00), Some(false), Some(false), Some(false));
 
         return r;
     }
 
     fn _alu_sub(&mut self, d8: u8, use_carry: bool) {
         let c = if use_carry && self.regs.get_flag(Flag::C) { 1 } else { 0 };
-	let a = self.regs.a;
-	let r = a.wrapping_sub(d8).wrapping_sub(c);
+        let a = self.regs.a;
+        let r = a.wrapping_sub(d8).wrapping_sub(c);
+        self.regs.a = r;
+
+        self.regs.set_flags(Some(r == 0), Some(true),
+            Some((a & 0x0f) < ((d8 & 0x0f) + c)),
+            Some((a as u16) < ((d8 as u16) + (c as u16))));
+    }
 
-	self.regs.set_flag(Flag::Z, r == 0);
-	self.regs.set_flag(Flag::H, (a & 0x0f) < ((d8 & 0x0f) + c));
-	self.regs.set_flag(Flag::N, true);
-	self.regs.set_flag(Flag::C, (a as u16) < ((d8 as u16) + (c as u16)));
+    fn _alu_or(&mut self, d8: u8) {
+        self.regs.a |= d8;
+        self.regs.set_flags(Some(self.regs.a == 0), Some(false), Some(false), Some(false));
     }
 
     fn _alu_xor(&mut self, d8: u8) {
         self.regs.a ^= d8;
-        self.regs.set_flag(Flag::Z, self.regs.a == 0);
-        self.regs.set_flag(Flag::N, false);
-        self.regs.set_flag(Flag::H, false);
-        self.regs.set_flag(Flag::C, false);
-    }
-
-    fn _get_res_from_cc(&self, cc: u8) -> bool {
-        let res = match cc {
-            0 => self.regs.get_flag(Flag::Z) == false,
-            1 => self.regs.get_flag(Flag::Z) != false,
-            2 => self.regs.get_flag(Flag::C) == false,
-            3 => self.regs.get_flag(Flag::C) != false,
-            _ => panic!("impossible <cc> index"),
-        };
+        self.regs.set_flags(Some(self.regs.a == 0), Some(false), Some(false), Some(false));
+    }
 
-        return res;
+    fn _get_res_from_cc(&self, cc: Condition) -> bool {
+        let flags = self.regs.flags();
+        return match cc {
+            Condition::NZ => !flags.z,
+            Condition::Z => flags.z,
+            Condition::NC => !flags.c,
+            Condition::C => flags.c,
+        };
     }
 
     fn _get_r8_from_r(&self, mmu: &Mmu, r: u8) -> u8 {
@@ -280,91 +1163,718 @@ impl Cpu {
             5 => self.regs.l,
             6 => mmu.read_byte(self.regs.hl()),
             7 => self.regs.a,
-            _ => panic!("impossible <r> index"),
+            _ => unreachable!("impossible <r> index"),
         };
     }
 
-    fn _get_r16_from_rp(&mut self, mmu: &mut Mmu, rp: u8) -> u16 {
+    fn _get_r16_from_rp(&self, rp: u8) -> u16 {
         return match rp {
             0 => self.regs.bc(),
             1 => self.regs.de(),
             2 => self.regs.hl(),
             3 => self.regs.sp,
-            _ => self._panic("impossible <rp> index"),
+            _ => unreachable!("impossible <rp> index"),
         };
     }
 
-    fn _get_r16_from_rp2(&mut self, mmu: &mut Mmu, rp2: u8) -> u16 {
+    fn _get_r16_from_rp2(&self, rp2: u8) -> u16 {
         return match rp2 {
             0 => self.regs.bc(),
             1 => self.regs.de(),
             2 => self.regs.hl(),
             3 => self.regs.af(),
-            _ => panic!("impossible <rp2> index")
+            _ => unreachable!("impossible <rp2> index"),
+        };
+    }
+
+    fn _set_r8_from_r(&mut self, mmu: &mut Mmu, r: u8, val: u8) {
+        match r {
+            0 => self.regs.b = val,
+            1 => self.regs.c = val,
+            2 => self.regs.d = val,
+            3 => self.regs.e = val,
+            4 => self.regs.h = val,
+            5 => self.regs.l = val,
+            6 => mmu.write_byte(self.regs.hl(), val),
+            7 => self.regs.a = val,
+            _ => unreachable!("impossible <r> index"),
+        };
+    }
+
+    fn _set_r16_from_rp(&mut self, rp: u8, val: u16) {
+        match rp {
+            0 => self.regs.set_bc(val),
+            1 => self.regs.set_de(val),
+            2 => self.regs.set_hl(val),
+            3 => self.regs.sp = val,
+            _ => unreachable!("impossible <rp> index"),
         };
     }
 
-    fn _set_r8_from_r(&mut self, mmu: &mut Mmu, r: u8, val: u8) {
-        match r {
-            0 => self.regs.b = val,
-            1 => self.regs.c = val,
-            2 => self.regs.d = val,
-            3 => self.regs.e = val,
-            4 => self.regs.h = val,
-            5 => self.regs.l = val,
-            6 => mmu.write_byte(self.regs.hl(), val),
-            7 => self.regs.a = val,
-            _ => panic!("impossible <r> index"),
-        };
+    fn _set_r16_from_rp2(&mut self, rp2: u8, val: u16) {
+        match rp2 {
+            0 => self.regs.set_bc(val),
+            1 => self.regs.set_de(val),
+            2 => self.regs.set_hl(val),
+            3 => self.regs.set_af(val),
+            _ => unreachable!("impossible <rp2> index"),
+        };
+    }
+
+    fn _stack_push(&mut self, mmu: &mut Mmu, word: u16) {
+        self.regs.sp = u16::wrapping_sub(self.regs.sp, 2);
+        mmu.write_word(self.regs.sp, word);
+    }
+
+    fn _stack_pop(&mut self, mmu: &mut Mmu) -> u16 {
+        let res = mmu.read_word(self.regs.sp);
+        self.regs.sp = u16::wrapping_add(self.regs.sp, 2);
+        return res;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::cpu::regs::Flags;
+    use crate::mem::joypad::Button;
+
+    #[test]
+    fn test_dump_state() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.set_af(0x01b0);
+        cpu.regs.set_bc(0x0013);
+        cpu.regs.set_de(0x00d8);
+        cpu.regs.set_hl(0x014d);
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc000, 0x00);
+        mmu.write_byte(0xc001, 0xc3);
+        mmu.write_byte(0xc002, 0x13);
+        mmu.write_byte(0xc003, 0x02);
+
+        assert_eq!(cpu.dump_state(&mmu),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C000 PCMEM:00,C3,13,02");
+    }
+
+    #[test]
+    fn test_ncycles_from_r() {
+        // (is_cb, register-form opcode, (HL)-form opcode, ncycles_r, ncycles_hl)
+        let cases = [
+            (false, 0x04, 0x34,  4, 12), // INC r[y]
+            (false, 0x05, 0x35,  4, 12), // DEC r[y]
+            (false, 0x06, 0x36,  8, 12), // LD r[y],n
+            (false, 0x41, 0x46,  4,  8), // LD r[y],r[z]
+            (false, 0x48, 0x70,  4,  8), // LD r[y],r[z]
+            (false, 0x80, 0x86,  4,  8), // ADD A,r[z]
+            (false, 0x88, 0x8e,  4,  8), // ADC A,r[z]
+            (false, 0x90, 0x96,  4,  8), // SUB r[z]
+            (false, 0x98, 0x9e,  4,  8), // SBC A,r[z]
+            (false, 0xa0, 0xa6,  4,  8), // AND r[z]
+            (false, 0xa8, 0xae,  4,  8), // XOR r[z]
+            (false, 0xb0, 0xb6,  4,  8), // OR r[z]
+            (false, 0xb8, 0xbe,  4,  8), // CP r[z]
+            (true,  0x00, 0x06,  8, 16), // RLC r[z]
+            (true,  0x10, 0x16,  8, 16), // RL r[z]
+            (true,  0x38, 0x3e,  8, 16), // SRL r[z]
+            (true,  0x40, 0x46,  8, 12), // BIT 0,r[z]
+            (true,  0x78, 0x7e,  8, 12), // BIT 7,r[z]
+            (true,  0x80, 0x86,  8, 16), // RES 0,r[z]
+            (true,  0xc0, 0xc6,  8, 16), // SET 0,r[z]
+        ];
+
+        for (is_cb, op_r, op_hl, ncycles_r, ncycles_hl) in cases.iter() {
+            let opcode_r = Opcode::from(*is_cb, *op_r).unwrap();
+            let opcode_hl = Opcode::from(*is_cb, *op_hl).unwrap();
+            assert_eq!(Cpu::_is_r_operand_hl(opcode_r), false);
+            assert_eq!(Cpu::_is_r_operand_hl(opcode_hl), true);
+            assert_eq!(Cpu::_get_ncycles_from_r(opcode_r, false), *ncycles_r,
+                "{}", opcode_r);
+            assert_eq!(Cpu::_get_ncycles_from_r(opcode_hl, true), *ncycles_hl,
+                "{}", opcode_hl);
+        }
+
+        // HALT sits in the LD r[y],r[z] block but is not a load.
+        let opcode_halt = Opcode::from(false, 0x76).unwrap();
+        assert_eq!(Cpu::_get_ncycles_from_r(opcode_halt, true), 4);
+    }
+
+    #[test]
+    fn test_dispatch_tables() {
+        // Only the illegal opcodes are left without a handler.
+        for value in 0..=0xff_u8 {
+            let expected = !Opcode::is_illegal(false, value);
+            assert_eq!(DISPATCH_UN[value as usize].is_some(), expected,
+                "un-prefixed opcode 0x{:02x}", value);
+            assert!(DISPATCH_CB[value as usize].is_some(),
+                "cb-prefixed opcode 0x{:02x}", value);
+        }
+
+        // HALT takes precedence over LD r[y], r[z].
+        let mut cpu = Cpu::new();
+        let mut mmu = Mmu::new();
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x76).unwrap());
+        assert!(cpu.is_halted);
+    }
+
+    #[test]
+    fn test_ld_nn_a() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let opcode_ld_nn_a = Opcode::from(false, 0xea).unwrap();
+
+        cpu.regs.pc = 0xc000;
+        cpu.regs.a = 0x42;
+        mmu.write_word(0xc000, 0xc100);
+        cpu._run_opcode_un(&mut mmu, opcode_ld_nn_a);
+        assert_eq!(mmu.last_write(), Some((0xc100, 0x42)));
+        assert_eq!(cpu.regs.pc, 0xc002);
+    }
+
+    #[test]
+    fn test_jp() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let opcode_jp_nn = Opcode::from(false, 0xc3).unwrap();
+        let opcode_jp_hl = Opcode::from(false, 0xe9).unwrap();
+
+        cpu.regs.pc = 0xc000;
+        mmu.write_word(0xc000, 0x1234);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, opcode_jp_nn), 16);
+        assert_eq!(cpu.regs.pc, 0x1234);
+
+        cpu.regs.set_hl(0x4321);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, opcode_jp_hl), 4);
+        assert_eq!(cpu.regs.pc, 0x4321);
+    }
+
+    #[test]
+    fn test_jp_cc() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let opcode_jp_nz = Opcode::from(false, 0xc2).unwrap();
+        let opcode_jp_c = Opcode::from(false, 0xda).unwrap();
+        mmu.write_word(0xc000, 0x1234);
+
+        // Taken.
+        cpu.regs.pc = 0xc000;
+        cpu.regs.set_flags(Some(false), None, None, Some(false));
+        assert_eq!(cpu._run_opcode_un(&mut mmu, opcode_jp_nz), 16);
+        assert_eq!(cpu.regs.pc, 0x1234);
+
+        // Not taken: the operand is skipped.
+        cpu.regs.pc = 0xc000;
+        assert_eq!(cpu._run_opcode_un(&mut mmu, opcode_jp_c), 12);
+        assert_eq!(cpu.regs.pc, 0xc002);
+    }
+
+    #[test]
+    fn test_call_cc() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let opcode_call_z = Opcode::from(false, 0xcc).unwrap();
+        mmu.write_word(0xc000, 0x1234);
+
+        // Not taken: nothing is pushed, and the operand is skipped.
+        cpu.regs.pc = 0xc000;
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.set_flags(Some(false), None, None, None);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, opcode_call_z), 12);
+        assert_eq!(cpu.regs.pc, 0xc002);
+        assert_eq!(cpu.regs.sp, 0xfffe);
+
+        // Taken.
+        cpu.regs.pc = 0xc000;
+        cpu.regs.set_flags(Some(true), None, None, None);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, opcode_call_z), 24);
+        assert_eq!(cpu.regs.pc, 0x1234);
+        assert_eq!(cpu.regs.sp, 0xfffc);
+        assert_eq!(mmu.read_word(0xfffc), 0xc002);
+    }
+
+    #[test]
+    fn test_ret_cc() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let opcode_ret_nc = Opcode::from(false, 0xd0).unwrap();
+        cpu.regs.sp = 0xfffc;
+        mmu.write_word(0xfffc, 0x1234);
+
+        // Not taken.
+        cpu.regs.pc = 0xc000;
+        cpu.regs.set_flags(None, None, None, Some(true));
+        assert_eq!(cpu._run_opcode_un(&mut mmu, opcode_ret_nc), 8);
+        assert_eq!(cpu.regs.pc, 0xc000);
+        assert_eq!(cpu.regs.sp, 0xfffc);
+
+        // Taken.
+        cpu.regs.set_flags(None, None, None, Some(false));
+        assert_eq!(cpu._run_opcode_un(&mut mmu, opcode_ret_nc), 20);
+        assert_eq!(cpu.regs.pc, 0x1234);
+        assert_eq!(cpu.regs.sp, 0xfffe);
+    }
+
+    #[test]
+    fn test_rst() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let opcode_rst_28 = Opcode::from(false, 0xef).unwrap();
+
+        cpu.regs.pc = 0xc123;
+        cpu.regs.sp = 0xfffe;
+        assert_eq!(cpu._run_opcode_un(&mut mmu, opcode_rst_28), 16);
+        assert_eq!(cpu.regs.pc, 0x0028);
+        assert_eq!(cpu.regs.sp, 0xfffc);
+        assert_eq!(mmu.read_word(0xfffc), 0xc123);
+    }
+
+    #[test]
+    fn test_ld_nn_sp() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.pc = 0xc000;
+        cpu.regs.sp = 0xbeef;
+        mmu.write_word(0xc000, 0xc100);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x08).unwrap()), 20);
+        assert_eq!(mmu.read_byte(0xc100), 0xef);
+        assert_eq!(mmu.read_byte(0xc101), 0xbe);
+        assert_eq!(cpu.regs.pc, 0xc002);
+    }
+
+    #[test]
+    fn test_pop_af() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // PUSH BC; POP AF: the lower nibble of F always reads as zero.
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.set_bc(0xffff);
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xc5).unwrap());
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xf1).unwrap());
+        assert_eq!(cpu.regs.a, 0xff);
+        assert_eq!(cpu.regs.f, 0xf0);
+        assert_eq!(cpu.regs.af(), 0xfff0);
+
+        // PUSH AF pushes the masked value back.
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xf5).unwrap());
+        assert_eq!(mmu.read_word(cpu.regs.sp), 0xfff0);
+    }
+
+    #[test]
+    fn test_ld_sp_hl() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.set_hl(0x1234);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xf9).unwrap()), 8);
+        assert_eq!(cpu.regs.sp, 0x1234);
+        assert_eq!(cpu.regs.hl(), 0x1234);
+    }
+
+    #[test]
+    fn test_dec_rp() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let flags = cpu.regs.flags();
+
+        cpu.regs.set_bc(0x0000);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x0b).unwrap()), 8);
+        assert_eq!(cpu.regs.bc(), 0xffff);
+
+        cpu.regs.set_hl(0x0100);
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x2b).unwrap());
+        assert_eq!(cpu.regs.hl(), 0x00ff);
+
+        assert_eq!(cpu.regs.flags(), flags);
+    }
+
+    #[test]
+    fn test_ld_a_nn() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.pc = 0xc000;
+        cpu.regs.a = 0x42;
+        mmu.write_word(0xc000, 0xc100);
+        mmu.write_word(0xc002, 0xc100);
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xea).unwrap());
+
+        cpu.regs.a = 0x00;
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xfa).unwrap()), 16);
+        assert_eq!(cpu.regs.a, 0x42);
+        assert_eq!(cpu.regs.pc, 0xc004);
+    }
+
+    #[test]
+    fn test_ldh_a() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc000, 0x85);
+        mmu.write_byte(0xff85, 0x42);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xf0).unwrap()), 12);
+        assert_eq!(cpu.regs.a, 0x42);
+        assert_eq!(cpu.regs.pc, 0xc001);
+
+        cpu.regs.c = 0x86;
+        mmu.write_byte(0xff86, 0x24);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xf2).unwrap()), 8);
+        assert_eq!(cpu.regs.a, 0x24);
+        assert_eq!(cpu.regs.pc, 0xc001);
+    }
+
+    #[test]
+    fn test_ld_bc_de_a() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.a = 0x42;
+        cpu.regs.set_bc(0xc000);
+        cpu.regs.set_de(0xc001);
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x02).unwrap());
+        assert_eq!(mmu.read_byte(0xc000), 0x42);
+        cpu.regs.a = 0x24;
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x12).unwrap());
+        assert_eq!(mmu.read_byte(0xc001), 0x24);
+    }
+
+    #[test]
+    fn test_ld_a_hl_inc_dec() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        mmu.write_byte(0xc000, 0x42);
+        mmu.write_byte(0xc001, 0x24);
+
+        cpu.regs.set_hl(0xc000);
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x2a).unwrap()), 8);
+        assert_eq!(cpu.regs.a, 0x42);
+        assert_eq!(cpu.regs.hl(), 0xc001);
+
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x3a).unwrap()), 8);
+        assert_eq!(cpu.regs.a, 0x24);
+        assert_eq!(cpu.regs.hl(), 0xc000);
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc000, 0x10);
+        mmu.write_byte(0xc001, 0x00);
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+        assert_eq!(cpu.is_stopped, true);
+        assert_eq!(cpu.regs.pc, 0xc002);
+
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 1);
+        assert_eq!(cpu.regs.pc, 0xc002);
+
+        // Pressing a button resumes the execution, even with IME unset.
+        mmu.write_byte(0xc002, 0x00);
+        mmu.write_byte(0xffff, Interrupt::Joypad.bit());
+        mmu.write_byte(0xff00, 0x10);
+        mmu.set_button(Button::A, true);
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+        assert_eq!(cpu.is_stopped, false);
+        assert_eq!(cpu.regs.pc, 0xc003);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0xc123;
+        cpu.regs.sp = 0xfffe;
+        mmu.write_byte(0xffff, 0x1f);
+        mmu.request_interrupt(Interrupt::Timer);
+        mmu.request_interrupt(Interrupt::Serial);
+
+        cpu.ime = true;
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 20);
+        assert_eq!(cpu.regs.pc, 0x0050);
+        assert_eq!(cpu.regs.sp, 0xfffc);
+        assert_eq!(mmu.read_word(0xfffc), 0xc123);
+        assert_eq!(cpu.ime, false);
+        assert_eq!(mmu.pending_interrupt(), Some(Interrupt::Serial));
+    }
+
+    #[test]
+    fn test_interrupt_disabled() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc000, 0x04); // INC B
+        mmu.request_interrupt(Interrupt::VBlank);
+
+        // Masked by IME.
+        mmu.write_byte(0xffff, 0x01);
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+        assert_eq!(cpu.regs.pc, 0xc001);
+
+        // Masked by IE.
+        cpu.ime = true;
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xffff, 0x00);
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+        assert_eq!(cpu.regs.pc, 0xc001);
+        assert_eq!(mmu.read_byte(0xff0f), 0xe1);
+    }
+
+    #[test]
+    fn test_di_ei() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0xc000;
+        cpu.regs.sp = 0xfffe;
+        mmu.write_byte(0xc000, 0xfb); // EI
+        mmu.write_byte(0xc001, 0x04); // INC B
+        mmu.write_byte(0xc002, 0x04); // INC B
+        mmu.write_byte(0xffff, 0x01);
+        mmu.request_interrupt(Interrupt::VBlank);
+
+        // The interrupt isn't taken right after EI...
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.regs.pc, 0xc001);
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.regs.pc, 0xc002);
+        assert_eq!(cpu.regs.b, 0x01);
+
+        // ... but after the instruction following it.
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 20);
+        assert_eq!(cpu.regs.pc, 0x0040);
+        assert_eq!(mmu.read_word(0xfffc), 0xc002);
+
+        // DI cancels a pending EI.
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc001, 0xf3); // DI
+        mmu.request_interrupt(Interrupt::VBlank);
+        cpu.step(&mut mmu).unwrap();
+        cpu.step(&mut mmu).unwrap();
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.regs.pc, 0xc003);
+        assert_eq!(cpu.ime, false);
+    }
+
+    #[test]
+    fn test_reti() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        cpu.regs.sp = 0xfffc;
+        mmu.write_word(0xfffc, 0x1234);
+
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xd9).unwrap()), 16);
+        assert_eq!(cpu.regs.pc, 0x1234);
+        assert_eq!(cpu.regs.sp, 0xfffe);
+        assert_eq!(cpu.ime, true);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        // 0x76 decodes as LD (HL),(HL) in the LD r[y],r[z] block, but
+        // is HALT; it must not be dispatched as a load.
+        let opcode_halt = Opcode::from(false, 0x76).unwrap();
+
+        cpu.regs.set_hl(0xc000);
+        mmu.write_byte(0xc000, 0x42);
+        // Sentinel: a load would overwrite it with (0xc000, 0x42).
+        mmu.write_byte(0xc001, 0x24);
+        assert_eq!(mmu.last_write(), Some((0xc001, 0x24)));
+        cpu._run_opcode_un(&mut mmu, opcode_halt);
+        assert_eq!(cpu.is_halted, true);
+        assert_eq!(mmu.last_write(), Some((0xc001, 0x24)));
+        assert_eq!(mmu.read_byte(0xc000), 0x42);
+    }
+
+    #[test]
+    fn test_halt_wake() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc000, 0x76); // HALT
+        mmu.write_byte(0xc001, 0x04); // INC B
+        mmu.write_byte(0xffff, 0x04);
+
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.is_halted, true);
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 1);
+        assert_eq!(cpu.regs.pc, 0xc001);
+
+        // With IME=0, the CPU resumes without servicing the interrupt.
+        mmu.request_interrupt(Interrupt::Timer);
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.is_halted, false);
+        assert_eq!(cpu.regs.pc, 0xc002);
+        assert_eq!(cpu.regs.b, 0x01);
+    }
+
+    #[test]
+    fn test_halt_wake_ime() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0xc000;
+        cpu.regs.sp = 0xfffe;
+        cpu.ime = true;
+        mmu.write_byte(0xc000, 0x76); // HALT
+        mmu.write_byte(0xffff, 0x04);
+
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 1);
+
+        // With IME=1, the CPU resumes and services the interrupt.
+        mmu.request_interrupt(Interrupt::Timer);
+        assert_eq!(cpu.step(&mut mmu).unwrap(), 20);
+        assert_eq!(cpu.is_halted, false);
+        assert_eq!(cpu.regs.pc, 0x0050);
+        assert_eq!(mmu.read_word(0xfffc), 0xc001);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc000, 0x76); // HALT
+        mmu.write_byte(0xc001, 0x04); // INC B
+        mmu.write_byte(0xffff, 0x04);
+        mmu.request_interrupt(Interrupt::Timer);
+
+        // The CPU doesn't halt, and INC B is executed twice.
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.is_halted, false);
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.regs.pc, 0xc001);
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.regs.pc, 0xc002);
+        assert_eq!(cpu.regs.b, 0x02);
     }
 
-    fn _set_r16_from_rp(&mut self, mmu: &mut Mmu, rp: u8, val: u16) {
-        match rp {
-            0 => self.regs.set_bc(val),
-            1 => self.regs.set_de(val),
-            2 => self.regs.set_hl(val),
-            3 => self.regs.sp = val,
-            _ => self._panic("impossible <rp> index"),
-        };
+    #[test]
+    fn test_get_res_from_cc() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.set_flags(Some(false), None, None, Some(false));
+        assert_eq!(cpu._get_res_from_cc(Condition::NZ), true);
+        assert_eq!(cpu._get_res_from_cc(Condition::Z), false);
+        assert_eq!(cpu._get_res_from_cc(Condition::NC), true);
+        assert_eq!(cpu._get_res_from_cc(Condition::C), false);
+
+        cpu.regs.set_flags(Some(true), None, None, Some(true));
+        assert_eq!(cpu._get_res_from_cc(Condition::NZ), false);
+        assert_eq!(cpu._get_res_from_cc(Condition::Z), true);
+        assert_eq!(cpu._get_res_from_cc(Condition::NC), false);
+        assert_eq!(cpu._get_res_from_cc(Condition::C), true);
+
+        // Reading the flags doesn't need a mutable borrow.
+        let (cpu_a, cpu_b) = (&cpu, &cpu);
+        assert_eq!(cpu_a.regs.get_flag(Flag::Z), cpu_b._get_res_from_cc(Condition::Z));
     }
 
-    fn _set_r16_from_rp2(&mut self, mmu: &mut Mmu, rp2: u8, val: u16) {
-        match rp2 {
-            0 => self.regs.set_bc(val),
-            1 => self.regs.set_de(val),
-            2 => self.regs.set_hl(val),
-            3 => self.regs.set_af(val),
-            _ => panic!("impossible <rp2> index")
-        };
+    #[test]
+    fn test_illegal_opcode_locks_up() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.set_strict(false);
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc000, 0xd3);
+        cpu.step(&mut mmu).unwrap();
+        assert_eq!(cpu.is_locked, true);
+        assert_eq!(cpu.is_halted, false);
+        assert_eq!(cpu.regs.pc, 0xc001);
+
+        for _ in 0..3 {
+            cpu.step(&mut mmu).unwrap();
+            assert_eq!(cpu.regs.pc, 0xc001);
+        }
     }
 
-    fn _stack_push(&mut self, mmu: &mut Mmu, word: u16) {
-        self.regs.sp = u16::wrapping_sub(self.regs.sp, 2);
-        mmu.write_word(self.regs.sp, word);
+    #[test]
+    fn test_illegal_opcode_strict() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc000, 0xd3);
+        let err = cpu.step(&mut mmu).unwrap_err();
+        assert_eq!(err, CpuError::UnknownOpcode { cb: false, byte: 0xd3, pc: 0xc000 });
+        assert_eq!(err.to_string(), "unknown un-prefixed opcode 0xd3 at pc=0xc000");
+        assert_eq!(cpu.regs.pc, 0xc000);
     }
 
-    fn _stack_pop(&mut self, mmu: &mut Mmu) -> u16 {
-        let res = mmu.read_word(self.regs.sp);
-        self.regs.sp = u16::wrapping_add(self.regs.sp, 2);
-        return res;
+    #[test]
+    fn test_nop() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.pc = 0xc000;
+        mmu.write_byte(0xc000, 0x00);
+        assert_eq!(cpu.step(&mut mmu), Ok(4));
+        assert_eq!(cpu.regs.pc, 0xc001);
     }
 
-    // Dumps the CPU state and exits.
-    fn _panic(&self, reason: &str) -> ! {
-        println!("=============== cpu panic ===============");
-        println!("regs: {:#0x?}", self.regs);
-        println!("-----------------------------------------");
-        println!("next_opcode_is_cb: {}", self.next_opcode_is_cb);
-        println!("-----------------------------------------");
-        println!("curr_opcode: {:#x?}", self.curr_opcode);
-        println!("=============== cpu panic ===============");
-        panic!("panic reason: {}", reason);
+    #[test]
+    fn test_cb_ncycles() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let opcode_rlc_b = Opcode::from(true, 0x00).unwrap();
+        let opcode_rlc_hl = Opcode::from(true, 0x06).unwrap();
+        let opcode_bit_0_b = Opcode::from(true, 0x40).unwrap();
+        let opcode_bit_0_hl = Opcode::from(true, 0x46).unwrap();
+
+        assert_eq!(Cpu::_get_ncycles_from_r(opcode_rlc_b, false), 8);
+        assert_eq!(Cpu::_get_ncycles_from_r(opcode_rlc_hl, true), 16);
+
+        // The opcode table lists 16 cycles for BIT y,(HL), but it only
+        // reads memory and takes 12.
+        cpu.regs.set_hl(0xc000);
+        assert_eq!(cpu._run_opcode_cb(&mut mmu, opcode_bit_0_b), 8);
+        assert_eq!(cpu._run_opcode_cb(&mut mmu, opcode_bit_0_hl), 12);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_step_hooks() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let pre_pcs = Rc::new(RefCell::new(Vec::new()));
+        let post_pcs = Rc::new(RefCell::new(Vec::new()));
+
+        // 0xc000: LD B,0x02
+        // 0xc002: DEC B
+        // 0xc003: JR NZ,-3
+        // 0xc005: LD C,B
+        let program = [0x06, 0x02, 0x05, 0x20, 0xfd, 0x48];
+        for (i, byte) in program.iter().enumerate() {
+            mmu.write_byte(0xc000 + i as u16, *byte);
+        }
+
+        let pcs = pre_pcs.clone();
+        cpu.set_pre_step_hook(Box::new(move |pc, _| pcs.borrow_mut().push(pc)));
+        let pcs = post_pcs.clone();
+        cpu.set_post_step_hook(Box::new(move |pc, opcode| {
+            pcs.borrow_mut().push((pc, opcode.value))
+        }));
+
+        cpu.regs.pc = 0xc000;
+        for _ in 0..6 {
+            cpu.step(&mut mmu).unwrap();
+        }
+
+        assert_eq!(*pre_pcs.borrow(),
+            vec![0xc000, 0xc002, 0xc003, 0xc002, 0xc003, 0xc005]);
+        assert_eq!(*post_pcs.borrow(), vec![
+            (0xc000, 0x06), (0xc002, 0x05), (0xc003, 0x20),
+            (0xc002, 0x05), (0xc003, 0x20), (0xc005, 0x48),
+        ]);
+    }
 
     #[test]
     fn test_dec_ry() {
@@ -434,6 +1944,239 @@ mod tests {
         assert_eq!(cpu.regs.hl(), 0x0000);
     }
 
+    #[test]
+    fn test_alu_add() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.a = 0x0f;
+        cpu._alu_add(0x01);
+        assert_eq!(cpu.regs.a, 0x10);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: true, c: false });
+
+        cpu.regs.a = 0xf0;
+        cpu._alu_add(0x10);
+        assert_eq!(cpu.regs.a, 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: true });
+
+        cpu.regs.a = 0xff;
+        cpu._alu_add(0x01);
+        assert_eq!(cpu.regs.a, 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: true, c: true });
+    }
+
+    #[test]
+    fn test_alu_adc() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.a = 0x0e;
+        cpu.regs.set_flags(None, None, None, Some(true));
+        cpu._alu_adc(0x01);
+        assert_eq!(cpu.regs.a, 0x10);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: true, c: false });
+
+        cpu.regs.a = 0xfe;
+        cpu.regs.set_flags(None, None, None, Some(true));
+        cpu._alu_adc(0x01);
+        assert_eq!(cpu.regs.a, 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: true, c: true });
+
+        cpu.regs.a = 0x0e;
+        cpu.regs.set_flags(None, None, None, Some(false));
+        cpu._alu_adc(0x01);
+        assert_eq!(cpu.regs.a, 0x0f);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
+    }
+
+    #[test]
+    fn test_alu_add16() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.set_hl(0x0fff);
+        cpu.regs.set_flags(Some(true), Some(true), None, None);
+        cpu._alu_add16(0x0001);
+        assert_eq!(cpu.regs.hl(), 0x1000);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: true, c: false });
+
+        cpu.regs.set_hl(0xffff);
+        cpu.regs.set_flags(Some(false), None, None, None);
+        cpu._alu_add16(0x0001);
+        assert_eq!(cpu.regs.hl(), 0x0000);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: true, c: true });
+
+        cpu.regs.set_hl(0x00ff);
+        cpu._alu_add16(0x0001);
+        assert_eq!(cpu.regs.hl(), 0x0100);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
+    }
+
+    #[test]
+    fn test_add_hl_rp() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // ADD HL,BC / ADD HL,HL / ADD HL,SP
+        cpu.regs.set_hl(0x1000);
+        cpu.regs.set_bc(0x0234);
+        cpu.regs.sp = 0x0001;
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x09).unwrap()), 8);
+        assert_eq!(cpu.regs.hl(), 0x1234);
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x29).unwrap());
+        assert_eq!(cpu.regs.hl(), 0x2468);
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x39).unwrap());
+        assert_eq!(cpu.regs.hl(), 0x2469);
+    }
+
+    #[test]
+    fn test_alu_add_sp() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.sp = 0xfff8;
+        cpu.regs.set_flags(Some(true), Some(true), None, None);
+        assert_eq!(cpu._alu_add_sp(0x08), 0x0000);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: true, c: true });
+
+        // Negative offsets: the flags come from the low byte addition,
+        // i.e. 0x00 + 0xff doesn't carry, while 0x01 + 0xff does.
+        cpu.regs.sp = 0x1000;
+        assert_eq!(cpu._alu_add_sp(0xff), 0x0fff);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
+
+        cpu.regs.sp = 0x1001;
+        assert_eq!(cpu._alu_add_sp(0xff), 0x1000);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: true, c: true });
+
+        cpu.regs.sp = 0x100f;
+        assert_eq!(cpu._alu_add_sp(0xf0), 0x0fff);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
+    }
+
+    #[test]
+    fn test_sp_offset() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        mmu.write_byte(0xc000, 0xfe);
+
+        cpu.regs.pc = 0xc000;
+        cpu.regs.sp = 0xd000;
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xf8).unwrap()), 12);
+        assert_eq!(cpu.regs.hl(), 0xcffe);
+        assert_eq!(cpu.regs.sp, 0xd000);
+
+        cpu.regs.pc = 0xc000;
+        assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0xe8).unwrap()), 16);
+        assert_eq!(cpu.regs.sp, 0xcffe);
+        assert_eq!(cpu.regs.pc, 0xc001);
+    }
+
+    #[test]
+    fn test_alu_and() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.a = 0x3c;
+        cpu.regs.set_flags(None, None, None, Some(true));
+        cpu._alu_and(0x0f);
+        assert_eq!(cpu.regs.a, 0x0c);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: true, c: false });
+
+        cpu._alu_and(0x30);
+        assert_eq!(cpu.regs.a, 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: true, c: false });
+    }
+
+    #[test]
+    fn test_alu_or() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.a = 0x30;
+        cpu._alu_or(0x0c);
+        assert_eq!(cpu.regs.a, 0x3c);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
+
+        cpu.regs.a = 0x00;
+        cpu._alu_or(0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: false });
+    }
+
+    #[test]
+    fn test_alu_block() {
+        // alu[y] B, with A=0x3c and B=0x0f.
+        let cases = [
+            (0x80, 0x4b), // ADD
+            (0x88, 0x4b), // ADC
+            (0x90, 0x2d), // SUB
+            (0x98, 0x2d), // SBC
+            (0xa0, 0x0c), // AND
+            (0xa8, 0x33), // XOR
+            (0xb0, 0x3f), // OR
+            (0xb8, 0x3c), // CP
+        ];
+        for (value, a) in cases.iter() {
+            let mut mmu = Mmu::new();
+            let mut cpu = Cpu::new();
+            let opcode = Opcode::from(false, *value).unwrap();
+            cpu.regs.a = 0x3c;
+            cpu.regs.b = 0x0f;
+            assert_eq!(cpu._run_opcode_un(&mut mmu, opcode), 4);
+            assert_eq!(cpu.regs.a, *a, "opcode 0x{:02x}", value);
+        }
+    }
+
+    #[test]
+    fn test_alu_d8() {
+        // alu[y] d8, with A=0x3c, d8=0x0f and the carry flag set.
+        let cases = [
+            (0xc6, 0x4b, Flags { z: false, n: false, h: true, c: false }), // ADD
+            (0xce, 0x4c, Flags { z: false, n: false, h: true, c: false }), // ADC
+            (0xd6, 0x2d, Flags { z: false, n: true, h: true, c: false }), // SUB
+            (0xde, 0x2c, Flags { z: false, n: true, h: true, c: false }), // SBC
+            (0xe6, 0x0c, Flags { z: false, n: false, h: true, c: false }), // AND
+            (0xee, 0x33, Flags { z: false, n: false, h: false, c: false }), // XOR
+            (0xf6, 0x3f, Flags { z: false, n: false, h: false, c: false }), // OR
+            (0xfe, 0x3c, Flags { z: false, n: true, h: true, c: false }), // CP
+        ];
+        for (value, a, flags) in cases.iter() {
+            let mut mmu = Mmu::new();
+            let mut cpu = Cpu::new();
+            let opcode = Opcode::from(false, *value).unwrap();
+            cpu.regs.pc = 0xc000;
+            cpu.regs.a = 0x3c;
+            cpu.regs.set_flags(None, None, None, Some(true));
+            mmu.write_byte(0xc000, 0x0f);
+            assert_eq!(cpu._run_opcode_un(&mut mmu, opcode), 8);
+            assert_eq!(cpu.regs.a, *a, "opcode 0x{:02x}", value);
+            assert_eq!(cpu.regs.flags(), *flags, "opcode 0x{:02x}", value);
+            assert_eq!(cpu.regs.pc, 0xc001);
+        }
+    }
+
+    #[test]
+    fn test_alu_daa() {
+        // (A, d8, is_sub, A', Z', C')
+        let cases = [
+            (0x09, 0x01, false, 0x10, false, false),
+            (0x15, 0x27, false, 0x42, false, false),
+            (0x99, 0x01, false, 0x00, true, true),
+            (0x50, 0x50, false, 0x00, true, true),
+            (0x08, 0x08, false, 0x16, false, false),
+            (0x10, 0x01, true, 0x09, false, false),
+            (0x42, 0x27, true, 0x15, false, false),
+            (0x00, 0x01, true, 0x99, false, true),
+        ];
+        for (a, d8, is_sub, res_a, z, c) in cases.iter() {
+            let mut cpu = Cpu::new();
+            cpu.regs.a = *a;
+            if *is_sub {
+                cpu._alu_sub(*d8, false);
+            } else {
+                cpu._alu_add(*d8);
+            }
+            cpu._alu_daa();
+            assert_eq!(cpu.regs.a, *res_a, "0x{:02x}, 0x{:02x}", a, d8);
+            assert_eq!(cpu.regs.flags(), Flags { z: *z, n: *is_sub, h: false, c: *c },
+                "0x{:02x}, 0x{:02x}", a, d8);
+        }
+    }
+
     #[test]
     fn test_alu_cp() {
         let mut mmu = Mmu::new();
@@ -449,33 +2192,275 @@ mod tests {
 
     #[test]
     fn test_alu_rl() {
-        // TODO
+        let mut cpu = Cpu::new();
+
+        cpu.regs.set_flags(None, None, None, Some(true));
+        assert_eq!(cpu._alu_rl(0x80), 0x01);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: true });
+
+        cpu.regs.set_flags(None, None, None, Some(false));
+        assert_eq!(cpu._alu_rl(0x80), 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: true });
+    }
+
+    #[test]
+    fn test_alu_rlc() {
+        let mut cpu = Cpu::new();
+
+        assert_eq!(cpu._alu_rlc(0x85), 0x0b);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: true });
+
+        cpu.regs.set_flags(None, None, None, Some(true));
+        assert_eq!(cpu._alu_rlc(0x00), 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: false });
+    }
+
+    #[test]
+    fn test_alu_rrc() {
+        let mut cpu = Cpu::new();
+
+        assert_eq!(cpu._alu_rrc(0x01), 0x80);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: true });
+
+        assert_eq!(cpu._alu_rrc(0x00), 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: false });
+    }
+
+    #[test]
+    fn test_alu_rr() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.set_flags(None, None, None, Some(true));
+        assert_eq!(cpu._alu_rr(0x02), 0x81);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
+
+        assert_eq!(cpu._alu_rr(0x01), 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: true });
+    }
+
+    #[test]
+    fn test_alu_sla() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.set_flags(None, None, None, Some(true));
+        assert_eq!(cpu._alu_sla(0x41), 0x82);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
+
+        assert_eq!(cpu._alu_sla(0x80), 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: true });
+    }
+
+    #[test]
+    fn test_alu_sra() {
+        let mut cpu = Cpu::new();
+
+        assert_eq!(cpu._alu_sra(0x81), 0xc0);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: true });
+
+        assert_eq!(cpu._alu_sra(0x01), 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: true });
+    }
+
+    #[test]
+    fn test_alu_srl() {
+        let mut cpu = Cpu::new();
+
+        assert_eq!(cpu._alu_srl(0x81), 0x40);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: true });
+
+        assert_eq!(cpu._alu_srl(0x01), 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: true });
+    }
+
+    #[test]
+    fn test_alu_swap() {
+        let mut cpu = Cpu::new();
+
+        cpu.regs.set_flags(None, None, None, Some(true));
+        assert_eq!(cpu._alu_swap(0x12), 0x21);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
+
+        assert_eq!(cpu._alu_swap(0x00), 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: false });
+    }
+
+    #[test]
+    fn test_rot_a() {
+        // (opcode, A, carry, A', carry')
+        let cases = [
+            (0x07, 0x80, false, 0x01, true), // RLCA
+            (0x07, 0x00, true, 0x00, false),
+            (0x0f, 0x01, false, 0x80, true), // RRCA
+            (0x0f, 0x00, true, 0x00, false),
+            (0x17, 0x80, false, 0x00, true), // RLA
+            (0x17, 0x40, true, 0x81, false),
+            (0x1f, 0x01, false, 0x00, true), // RRA
+            (0x1f, 0x02, true, 0x81, false),
+        ];
+        for (value, a, c, res_a, res_c) in cases.iter() {
+            let mut mmu = Mmu::new();
+            let mut cpu = Cpu::new();
+            cpu.regs.a = *a;
+            cpu.regs.b = 0x42;
+            cpu.regs.set_flags(Some(true), Some(true), Some(true), Some(*c));
+
+            assert_eq!(cpu._run_opcode_un(&mut mmu, Opcode::from(false, *value).unwrap()), 4);
+            assert_eq!(cpu.regs.a, *res_a, "opcode 0x{:02x}", value);
+            assert_eq!(cpu.regs.b, 0x42);
+            assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: *res_c },
+                "opcode 0x{:02x}", value);
+        }
+    }
+
+    #[test]
+    fn test_cpl() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.a = 0x5a;
+        cpu.regs.set_flags(Some(true), Some(false), Some(false), Some(true));
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x2f).unwrap());
+        assert_eq!(cpu.regs.a, 0xa5);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: true, h: true, c: true });
+    }
+
+    #[test]
+    fn test_scf() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        cpu.regs.set_flags(Some(true), Some(true), Some(true), Some(false));
+        cpu._run_opcode_un(&mut mmu, Opcode::from(false, 0x37).unwrap());
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: true });
+    }
+
+    #[test]
+    fn test_ccf() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let opcode_ccf = Opcode::from(false, 0x3f).unwrap();
+
+        cpu.regs.set_flags(Some(false), Some(true), Some(true), Some(false));
+        cpu._run_opcode_un(&mut mmu, opcode_ccf);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: true });
+        cpu._run_opcode_un(&mut mmu, opcode_ccf);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
+    }
+
+    #[test]
+    fn test_bit() {
+        // BIT y,r[z] over every bit and every register, including (HL).
+        for val in [0x00, 0xff, 0x5a, 0x81].iter() {
+            for y in 0..8 {
+                for z in 0..8 {
+                    for c in [false, true].iter() {
+                        let mut mmu = Mmu::new();
+                        let mut cpu = Cpu::new();
+                        let value = 0x40 + y * 8 + z;
+                        cpu.regs.set_hl(0xc000);
+                        cpu._set_r8_from_r(&mut mmu, z, *val);
+                        cpu.regs.set_flags(None, Some(true), Some(false), Some(*c));
+
+                        cpu._run_opcode_cb(&mut mmu, Opcode::from(true, value).unwrap());
+                        let z_flag = (*val >> y) & 1 == 0;
+                        assert_eq!(cpu.regs.flags(), Flags { z: z_flag, n: false, h: true, c: *c },
+                            "opcode 0x{:02x}, r=0x{:02x}", value, val);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_res_set() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        let flags = Flags { z: true, n: false, h: true, c: false };
+        cpu.regs.set_flags(Some(flags.z), Some(flags.n), Some(flags.h), Some(flags.c));
+
+        // RES 0,B / RES 7,B
+        cpu.regs.b = 0xff;
+        cpu._run_opcode_cb(&mut mmu, Opcode::from(true, 0x80).unwrap());
+        assert_eq!(cpu.regs.b, 0xfe);
+        cpu._run_opcode_cb(&mut mmu, Opcode::from(true, 0xb8).unwrap());
+        assert_eq!(cpu.regs.b, 0x7e);
+
+        // SET 0,B / SET 7,B
+        cpu.regs.b = 0x00;
+        cpu._run_opcode_cb(&mut mmu, Opcode::from(true, 0xc0).unwrap());
+        assert_eq!(cpu.regs.b, 0x01);
+        cpu._run_opcode_cb(&mut mmu, Opcode::from(true, 0xf8).unwrap());
+        assert_eq!(cpu.regs.b, 0x81);
+
+        // RES 3,(HL) / SET 4,(HL)
+        cpu.regs.set_hl(0xc000);
+        mmu.write_byte(0xc000, 0x0f);
+        assert_eq!(cpu._run_opcode_cb(&mut mmu, Opcode::from(true, 0x9e).unwrap()), 16);
+        assert_eq!(mmu.read_byte(0xc000), 0x07);
+        assert_eq!(cpu._run_opcode_cb(&mut mmu, Opcode::from(true, 0xe6).unwrap()), 16);
+        assert_eq!(mmu.read_byte(0xc000), 0x17);
+
+        // Flags are untouched.
+        assert_eq!(cpu.regs.flags(), flags);
+    }
+
+    #[test]
+    fn test_rot_block() {
+        // rot[y] (HL), with (HL)=0x81.
+        let cases = [
+            (0x06, 0x03), // RLC
+            (0x0e, 0xc0), // RRC
+            (0x16, 0x02), // RL
+            (0x1e, 0x40), // RR
+            (0x26, 0x02), // SLA
+            (0x2e, 0xc0), // SRA
+            (0x36, 0x18), // SWAP
+            (0x3e, 0x40), // SRL
+        ];
+        for (value, r) in cases.iter() {
+            let mut mmu = Mmu::new();
+            let mut cpu = Cpu::new();
+            let opcode = Opcode::from(true, *value).unwrap();
+            cpu.regs.set_hl(0xc000);
+            mmu.write_byte(0xc000, 0x81);
+            assert_eq!(cpu._run_opcode_cb(&mut mmu, opcode), 16);
+            assert_eq!(mmu.read_byte(0xc000), *r, "opcode 0x{:02x}", value);
+        }
     }
 
     #[test]
     fn test_alu_sub() {
-        // TODO
+        let mut cpu = Cpu::new();
+
+        cpu.regs.a = 0x10;
+        cpu._alu_sub(0x01, false);
+        assert_eq!(cpu.regs.a, 0x0f);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: true, h: true, c: false });
+
+        cpu.regs.a = 0x10;
+        cpu._alu_sub(0x20, false);
+        assert_eq!(cpu.regs.a, 0xf0);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: true, h: false, c: true });
+
+        cpu.regs.a = 0x10;
+        cpu.regs.set_flags(None, None, None, Some(true));
+        cpu._alu_sub(0x0f, true);
+        assert_eq!(cpu.regs.a, 0x00);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: true, h: true, c: false });
     }
 
     #[test]
     fn test_alu_xor() {
-        let mut mmu = Mmu::new();
         let mut cpu = Cpu::new();
 
         cpu.regs.a = 0x0f;
         cpu._alu_xor(0xf0);
         assert_eq!(cpu.regs.a, 0xff);
-        assert_eq!(cpu.regs.get_flag(Flag::Z), false);
-        assert_eq!(cpu.regs.get_flag(Flag::N), false);
-        assert_eq!(cpu.regs.get_flag(Flag::H), false);
-        assert_eq!(cpu.regs.get_flag(Flag::C), false);
+        assert_eq!(cpu.regs.flags(), Flags { z: false, n: false, h: false, c: false });
 
         cpu.regs.a = 0x0f;
         cpu._alu_xor(0x0f);
         assert_eq!(cpu.regs.a, 0x00);
-        assert_eq!(cpu.regs.get_flag(Flag::Z), true);
-        assert_eq!(cpu.regs.get_flag(Flag::N), false);
-        assert_eq!(cpu.regs.get_flag(Flag::H), false);
-        assert_eq!(cpu.regs.get_flag(Flag::C), false);
+        assert_eq!(cpu.regs.flags(), Flags { z: true, n: false, h: false, c: false });
     }
 }
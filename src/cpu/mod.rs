@@ -1,9 +1,10 @@
 mod cpu;
-pub use self::cpu::Cpu;
+pub use self::cpu::{Cpu, CpuError};
 
 mod opcode;
 pub use self::opcode::Opcode;
 
 mod regs;
+pub use self::regs::Condition;
 pub use self::regs::Flag;
 pub use self::regs::Regs;
@@ -1,5 +1,13 @@
 use std::fmt;
 
+use crate::cpu::Cpu;
+
+// Opcodes which do not exist on the LR35902.  On hardware, executing any
+// of them locks up the CPU.
+const ILLEGAL_OPCODES: [u8; 11] = [
+    0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd,
+];
+
 /// Represents an opcode for the LR35902 CPU.
 #[derive(Debug)]
 pub struct Opcode {
@@ -25,11 +33,46 @@ impl Opcode {
         };
     }
 
+    /// Returns |true| iff. |value| does not exist on the LR35902.
+    pub fn is_illegal(is_cb: bool, value: u8) -> bool {
+        !is_cb && ILLEGAL_OPCODES.contains(&value)
+    }
+
+    /// Returns |true| iff. the CPU is able to execute |value|.
+    pub fn is_implemented(is_cb: bool, value: u8) -> bool {
+        Cpu::has_handler(is_cb, value)
+    }
+
     pub fn x(&self) -> u8 { (self.value & 0b1100_0000) >> 6 }
     pub fn y(&self) -> u8 { (self.value & 0b0011_1000) >> 3 }
     pub fn z(&self) -> u8 { self.value & 0b0000_0111 }
     pub fn p(&self) -> u8 { (self.value & 0b0011_0000) >> 4 }
     pub fn q(&self) -> u8 { (self.value & 0b0000_1000) >> 3 }
+
+    /// Returns the number of bytes taken by the instruction, including
+    /// its operands.  CB-prefixed opcodes count the 0xCB prefix byte.
+    pub fn len(&self) -> u8 {
+        if self.is_cb {
+            return 2;
+        }
+
+        return match (self.x(), self.y(), self.z(), self.q()) {
+            (0, 1, 0, _) => 3,      // LD (nn), SP
+            (0, 2, 0, _) => 2,      // STOP
+            (0, 3..=7, 0, _) => 2,  // JR d / JR cc[y-4], d
+            (0, _, 1, 0) => 3,      // LD rp[p], nn
+            (0, _, 6, _) => 2,      // LD r[y], n
+            (3, 4..=7, 0, _) => 2,  // LDH (n),A / ADD SP,d / LDH A,(n) / LD HL,SP+d
+            (3, 0..=3, 2, _) => 3,  // JP cc[y], nn
+            (3, 5, 2, _) => 3,      // LD (nn), A
+            (3, 7, 2, _) => 3,      // LD A, (nn)
+            (3, 0, 3, _) => 3,      // JP nn
+            (3, 0..=3, 4, _) => 3,  // CALL cc[y], nn
+            (3, 1, 5, _) => 3,      // CALL nn
+            (3, _, 6, _) => 2,      // alu[y] n
+            _ => 1,
+        };
+    }
 }
 
 impl fmt::Display for Opcode {
@@ -566,3 +609,63 @@ pub const TABLE_CB_PREFIXED: [Option<Opcode>; 256] = [
 	Some( Opcode { is_cb:  true, value: 0xff, ncycles: ( 8,  8), mnemo: "SET 7,A" } ),
 ];
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_illegal() {
+        for value in 0..=255 {
+            assert_eq!(Opcode::is_illegal(false, value),
+                Opcode::from(false, value).is_none());
+            assert_eq!(Opcode::is_illegal(true, value), false);
+        }
+    }
+
+    #[test]
+    fn test_is_implemented() {
+        let missing_un: Vec<String> = (0..=255)
+            .filter(|value| !Opcode::is_implemented(false, *value))
+            .map(|value| format!("0x{:02x}", value))
+            .collect();
+        println!("unimplemented un-prefixed opcodes: {}", missing_un.join(", "));
+        assert_eq!(256 - missing_un.len(), 245);
+
+        for value in 0..=255 {
+            assert_eq!(Opcode::is_implemented(true, value), true);
+            // Illegal opcodes are never implemented.
+            if Opcode::is_illegal(false, value) {
+                assert_eq!(Opcode::is_implemented(false, value), false);
+            }
+        }
+    }
+
+    #[test]
+    fn test_len() {
+        // 1-byte opcodes.
+        assert_eq!(Opcode::from(false, 0x00).unwrap().len(), 1); // NOP
+        assert_eq!(Opcode::from(false, 0x04).unwrap().len(), 1); // INC B
+        assert_eq!(Opcode::from(false, 0x78).unwrap().len(), 1); // LD A,B
+        assert_eq!(Opcode::from(false, 0xe2).unwrap().len(), 1); // LD (C),A
+        assert_eq!(Opcode::from(false, 0xc9).unwrap().len(), 1); // RET
+
+        // 2-byte opcodes.
+        assert_eq!(Opcode::from(false, 0x06).unwrap().len(), 2); // LD B,d8
+        assert_eq!(Opcode::from(false, 0x18).unwrap().len(), 2); // JR r8
+        assert_eq!(Opcode::from(false, 0x20).unwrap().len(), 2); // JR NZ,r8
+        assert_eq!(Opcode::from(false, 0xe0).unwrap().len(), 2); // LDH (a8),A
+        assert_eq!(Opcode::from(false, 0xfe).unwrap().len(), 2); // CP d8
+
+        // 3-byte opcodes.
+        assert_eq!(Opcode::from(false, 0x01).unwrap().len(), 3); // LD BC,d16
+        assert_eq!(Opcode::from(false, 0x08).unwrap().len(), 3); // LD (a16),SP
+        assert_eq!(Opcode::from(false, 0xc3).unwrap().len(), 3); // JP a16
+        assert_eq!(Opcode::from(false, 0xcd).unwrap().len(), 3); // CALL a16
+        assert_eq!(Opcode::from(false, 0xea).unwrap().len(), 3); // LD (a16),A
+
+        // CB-prefixed opcodes.
+        assert_eq!(Opcode::from(true, 0x11).unwrap().len(), 2); // RL C
+        assert_eq!(Opcode::from(true, 0x7c).unwrap().len(), 2); // BIT 7,H
+    }
+}
@@ -9,6 +9,43 @@ pub enum Flag {
     C = 0b0001_0000,
 }
 
+/// Enumerates the conditions (cc) evaluated by the conditional jumps,
+/// calls and returns, in the order of their encoding.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Condition {
+    NZ, // Z flag is reset.
+    Z,  // Z flag is set.
+    NC, // C flag is reset.
+    C,  // C flag is set.
+}
+
+impl Condition {
+    pub fn from_index(cc: u8) -> Condition {
+        return match cc {
+            0 => Condition::NZ,
+            1 => Condition::Z,
+            2 => Condition::NC,
+            3 => Condition::C,
+            _ => panic!("impossible <cc> index"),
+        };
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Snapshot of the Z, N, H, and C flags; mostly useful for assertions.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Flags {
+    pub z: bool,
+    pub n: bool,
+    pub h: bool,
+    pub c: bool,
+}
+
 #[derive(Debug, Default)]
 // Represents the LR35902's registers.
 pub struct Regs {
@@ -62,7 +99,7 @@ impl Regs {
 
     pub fn set_af(&mut self, raw: u16) {
         self.a = ((raw & 0xff00) >> 8) as u8;
-        self.f = (raw & 0x00ff) as u8;
+        self.set_f((raw & 0x00ff) as u8);
     }
 
     pub fn set_bc(&mut self, raw: u16) {
@@ -80,6 +117,12 @@ impl Regs {
         self.l = (raw & 0x00ff) as u8;
     }
 
+    // Sets the whole flag register; the lower nibble of F is not wired
+    // to anything and always reads as zero.
+    pub fn set_f(&mut self, val: u8) {
+        self.f = val & 0xf0;
+    }
+
     pub fn set_flag(&mut self, flag: Flag, set: bool) {
         if set {
             self.f |= flag as u8;
@@ -88,9 +131,27 @@ impl Regs {
         }
     }
 
+    // Sets the Z, N, H, and C flags at once; |None| leaves a flag unchanged.
+    pub fn set_flags(&mut self, z: Option<bool>, n: Option<bool>,
+                     h: Option<bool>, c: Option<bool>) {
+        if let Some(z) = z { self.set_flag(Flag::Z, z); }
+        if let Some(n) = n { self.set_flag(Flag::N, n); }
+        if let Some(h) = h { self.set_flag(Flag::H, h); }
+        if let Some(c) = c { self.set_flag(Flag::C, c); }
+    }
+
     pub fn get_flag(&self, flag: Flag) -> bool {
         (self.f & (flag as u8)) != 0
     }
+
+    pub fn flags(&self) -> Flags {
+        return Flags {
+            z: self.get_flag(Flag::Z),
+            n: self.get_flag(Flag::N),
+            h: self.get_flag(Flag::H),
+            c: self.get_flag(Flag::C),
+        };
+    }
 }
 
 impl fmt::Display for Regs {
@@ -115,6 +176,15 @@ impl fmt::Display for Regs {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_condition() {
+        assert_eq!(Condition::from_index(0), Condition::NZ);
+        assert_eq!(Condition::from_index(1), Condition::Z);
+        assert_eq!(Condition::from_index(2), Condition::NC);
+        assert_eq!(Condition::from_index(3), Condition::C);
+        assert_eq!(format!("{}", Condition::NZ), "NZ");
+    }
+
     #[test]
     fn test_flag() {
         let mut regs = Regs::default();
@@ -152,6 +222,47 @@ mod tests {
         assert_eq!(regs.get_flag(Flag::C), false);
     }
 
+    #[test]
+    fn test_set_flags() {
+        let mut regs = Regs::default();
+
+        regs.set_flag(Flag::N, true);
+        regs.set_flag(Flag::H, true);
+        regs.set_flags(Some(true), None, Some(false), None);
+        assert_eq!(regs.get_flag(Flag::Z), true);
+        assert_eq!(regs.get_flag(Flag::N), true);
+        assert_eq!(regs.get_flag(Flag::H), false);
+        assert_eq!(regs.get_flag(Flag::C), false);
+
+        regs.set_flags(None, None, None, Some(true));
+        assert_eq!(regs.f, 0b1101_0000);
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut regs = Regs::default();
+        assert_eq!(regs.flags(), Flags { z: false, n: false, h: false, c: false });
+
+        regs.set_f(0b1010_0000);
+        assert_eq!(regs.flags(), Flags { z: true, n: false, h: true, c: false });
+    }
+
+    #[test]
+    fn test_set_f() {
+        let mut regs = Regs::default();
+
+        regs.set_f(0x3f);
+        assert_eq!(regs.f, 0x30);
+        assert_eq!(regs.get_flag(Flag::Z), false);
+        assert_eq!(regs.get_flag(Flag::N), false);
+        assert_eq!(regs.get_flag(Flag::H), true);
+        assert_eq!(regs.get_flag(Flag::C), true);
+
+        regs.set_af(0x12ff);
+        assert_eq!(regs.a, 0x12);
+        assert_eq!(regs.f, 0xf0);
+    }
+
     #[test]
     fn test_reg16() {
         let mut regs = Regs::default();
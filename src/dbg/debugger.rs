@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+
+use crate::mem::WatchHit;
+
+/// Enumerates the reasons for the debugger to return control.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BreakReason {
+    /// The PC reached the breakpoint at the given address.
+    Breakpoint(u16),
+    /// An instruction wrote to a watched address.
+    Watchpoint(WatchHit),
+    /// An instruction was executed in step mode.
+    Step,
+    /// The CPU kept servicing interrupts without making progress.
+    InterruptStorm,
+}
+
+/// Represents the debugger attached to a system: it holds the PC
+/// breakpoints, and whether execution stops after each instruction.
+pub struct Debugger {
+    breakpoints: HashSet<u16>,
+    is_stepping: bool,
+}
+
+impl Debugger {
+    /// Creates a debugger without breakpoints, out of step mode.
+    pub fn new() -> Debugger {
+        return Debugger {
+            breakpoints: HashSet::new(),
+            is_stepping: false,
+        };
+    }
+
+    /// Adds a breakpoint at |addr|.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Removes the breakpoint at |addr|, if any.
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    /// Sets whether execution stops after each instruction.
+    pub fn set_stepping(&mut self, is_stepping: bool) {
+        self.is_stepping = is_stepping;
+    }
+
+    /// Returns why execution must stop with the PC at |pc|, after an
+    /// instruction was executed; returns |None| to keep running.
+    pub fn check(&self, pc: u16) -> Option<BreakReason> {
+        if self.breakpoints.contains(&pc) {
+            return Some(BreakReason::Breakpoint(pc));
+        }
+        if self.is_stepping {
+            return Some(BreakReason::Step);
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breakpoints() {
+        let mut dbg = Debugger::new();
+        assert_eq!(dbg.check(0x0100), None);
+
+        dbg.add_breakpoint(0x0100);
+        assert_eq!(dbg.check(0x0100), Some(BreakReason::Breakpoint(0x0100)));
+        assert_eq!(dbg.check(0x0101), None);
+
+        dbg.remove_breakpoint(0x0100);
+        assert_eq!(dbg.check(0x0100), None);
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut dbg = Debugger::new();
+        dbg.add_breakpoint(0x0100);
+        dbg.set_stepping(true);
+        assert_eq!(dbg.check(0x0101), Some(BreakReason::Step));
+        assert_eq!(dbg.check(0x0100), Some(BreakReason::Breakpoint(0x0100)));
+        dbg.set_stepping(false);
+        assert_eq!(dbg.check(0x0101), None);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::cpu::Opcode;
+use crate::mem::Mmu;
+
+// Prefix of the CB-prefixed opcodes.
+const CB_PREFIX: u8 = 0xcb;
+
+/// Disassembles the instruction at |addr|; returns its mnemonic, with the
+/// operands read from memory, and the number of bytes it takes.
+///
+/// Relative jumps show their target address.  Bytes which don't decode to
+/// an instruction are shown as "DB 0xNN".
+pub fn disassemble(mmu: &Mmu, addr: u16) -> (String, u16) {
+    let value = mmu.read_byte(addr);
+    let opcode = if value == CB_PREFIX {
+        Opcode::from(true, mmu.read_byte(addr.wrapping_add(1)))
+    } else {
+        Opcode::from(false, value)
+    };
+
+    let opcode = match opcode {
+        Some(opcode) => opcode,
+        None => return (format!("DB 0x{:02X}", value), 1),
+    };
+
+    let len = opcode.len() as u16;
+    if opcode.is_cb {
+        return (opcode.mnemo.to_string(), len);
+    }
+
+    let d8 = mmu.read_byte(addr.wrapping_add(1));
+    let d16 = mmu.read_word(addr.wrapping_add(1));
+    let mnemo = opcode.mnemo;
+    let text = if mnemo.contains("d16") || mnemo.contains("a16") {
+        mnemo.replace("d16", &format!("0x{:04X}", d16))
+            .replace("a16", &format!("0x{:04X}", d16))
+    } else if mnemo.contains("d8") {
+        mnemo.replace("d8", &format!("0x{:02X}", d8))
+    } else if mnemo.contains("a8") {
+        mnemo.replace("a8", &format!("0x{:04X}", 0xff00 + d8 as u16))
+    } else if mnemo.starts_with("JR") {
+        let target = addr.wrapping_add(len).wrapping_add(d8 as i8 as u16);
+        mnemo.replace("r8", &format!("0x{:04X}", target))
+    } else if mnemo.contains("+r8") {
+        mnemo.replace("+r8", &format!("{:+}", d8 as i8))
+    } else if mnemo.contains("r8") {
+        mnemo.replace("r8", &format!("{}", d8 as i8))
+    } else {
+        mnemo.to_string()
+    };
+
+    return (text, len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WRAM_ADDR: u16 = 0xc000;
+
+    // Returns the disassembly of |bytes|.
+    fn disassemble_bytes(bytes: &[u8]) -> (String, u16) {
+        let mut mmu = Mmu::new();
+        for (i, byte) in bytes.iter().enumerate() {
+            mmu.write_byte(WRAM_ADDR + i as u16, *byte);
+        }
+        return disassemble(&mmu, WRAM_ADDR);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let cases: [(&[u8], &str, u16); 14] = [
+            (&[0x00], "NOP", 1),
+            (&[0x06, 0x12], "LD B,0x12", 2),
+            (&[0x21, 0x34, 0x12], "LD HL,0x1234", 3),
+            (&[0xc3, 0x00, 0xc0], "JP 0xC000", 3),
+            (&[0xea, 0x00, 0xc0], "LD (0xC000),A", 3),
+            (&[0xe0, 0x42], "LDH (0xFF42),A", 2),
+            (&[0x18, 0xfe], "JR 0xC000", 2),
+            (&[0x20, 0x05], "JR NZ,0xC007", 2),
+            (&[0xe8, 0xfe], "ADD SP,-2", 2),
+            (&[0xf8, 0x05], "LD HL,SP+5", 2),
+            (&[0x10, 0x00], "STOP 0", 2),
+            (&[0xcb, 0x7c], "BIT 7,H", 2),
+            (&[0xcb, 0x11], "RL C", 2),
+            (&[0xd3], "DB 0xD3", 1),
+        ];
+        for (bytes, text, len) in cases.iter() {
+            assert_eq!(disassemble_bytes(bytes), (text.to_string(), *len), "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_disassemble_stream() {
+        let mut mmu = Mmu::new();
+        let bytes = [0x31, 0xfe, 0xff, 0xaf, 0x21, 0xff, 0x9f];
+        for (i, byte) in bytes.iter().enumerate() {
+            mmu.write_byte(WRAM_ADDR + i as u16, *byte);
+        }
+
+        let mut addr = WRAM_ADDR;
+        let mut texts = Vec::new();
+        for _ in 0..3 {
+            let (text, len) = disassemble(&mmu, addr);
+            texts.push(text);
+            addr += len;
+        }
+        assert_eq!(texts, vec!["LD SP,0xFFFE", "XOR A", "LD HL,0x9FFF"]);
+    }
+}
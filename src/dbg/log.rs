@@ -1,3 +1,184 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+
+/// Enumerates the log levels, from the most to the least severe.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Trace,
+}
+
+impl Level {
+    /// Parses a level from its name (e.g., "warn"); returns |None| if the
+    /// name is unknown.
+    pub fn from_name(name: &str) -> Option<Level> {
+        return match name.to_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        };
+    }
+
+    fn _tag(&self) -> &'static str {
+        match self {
+            Level::Error => "ERRO",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Trace => "TRCE",
+        }
+    }
+}
+
+// State of the logger: messages are written to |sink| (stdout by default)
+// if they are at most as verbose as |level|, and come from one of the
+// |subsystems| (all of them when empty).
+struct Logger {
+    level: Level,
+    subsystems: Vec<String>,
+    sink: Option<Box<dyn Write>>,
+}
+
+// The emulator runs on a single thread; keeping the logger per thread lets
+// the tests configure it independently of each other.
+thread_local! {
+    static LOGGER: RefCell<Logger> = RefCell::new(Logger {
+        level: Level::Info,
+        subsystems: Vec::new(),
+        sink: None,
+    });
+}
+
+/// Sets the most verbose level logged; defaults to |Level::Info|.
+pub fn set_level(level: Level) {
+    LOGGER.with(|logger| logger.borrow_mut().level = level);
+}
+
+/// Restricts logging to the messages of |subsystems| (e.g., "cpu"); an
+/// empty list logs all the subsystems.
+pub fn set_subsystems(subsystems: &[&str]) {
+    LOGGER.with(|logger| {
+        logger.borrow_mut().subsystems = subsystems.iter().map(|s| s.to_string()).collect();
+    });
+}
+
+/// Writes the messages to |sink| instead of stdout.
+pub fn set_sink(sink: Box<dyn Write>) {
+    LOGGER.with(|logger| logger.borrow_mut().sink = Some(sink));
+}
+
+/// Returns |true| iff. a message of |subsystem| at |level| is logged; it
+/// allows skipping the formatting of messages which would be dropped.
+pub fn is_enabled(level: Level, subsystem: &str) -> bool {
+    LOGGER.with(|logger| {
+        let logger = logger.borrow();
+        return level <= logger.level
+            && (logger.subsystems.is_empty() || logger.subsystems.iter().any(|s| s == subsystem));
+    })
+}
+
+/// Logs |msg| at |level|, if enabled for |module_name|.
+pub fn log(level: Level, module_name: &str, module_func: &str, msg: &str) {
+    if !is_enabled(level, module_name) {
+        return;
+    }
+
+    let line = format!("|{}| {:10} | {:10} | {}", level._tag(), module_name, module_func, msg);
+    LOGGER.with(|logger| {
+        let res = match logger.borrow_mut().sink.as_mut() {
+            Some(sink) => writeln!(sink, "{}", line),
+            None => writeln!(io::stdout(), "{}", line),
+        };
+        res.expect("failed to write the log");
+    });
+}
+
+pub fn warn(module_name: &str, module_func: &str, msg: &str) {
+    log(Level::Warn, module_name, module_func, msg);
+}
+
 pub fn info(module_name: &str, module_func: &str, msg: &str) {
-    println!("|INFO| {:10} | {:10} | {}", module_name, module_func, msg);
+    log(Level::Info, module_name, module_func, msg);
+}
+
+pub fn trace(module_name: &str, module_func: &str, msg: &str) {
+    log(Level::Trace, module_name, module_func, msg);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    // A sink shared with the test, to inspect the logged messages.
+    #[derive(Clone)]
+    pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        pub fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+                .lines().map(|line| line.to_string()).collect()
+        }
+    }
+
+    // Captures the messages logged by the current thread.
+    pub fn capture() -> Capture {
+        let capture = Capture(Rc::new(RefCell::new(Vec::new())));
+        set_sink(Box::new(capture.clone()));
+        return capture;
+    }
+
+    #[test]
+    fn test_level() {
+        let capture = capture();
+        info("cpu", "step", "first");
+        trace("cpu", "step", "dropped");
+
+        set_level(Level::Warn);
+        info("cpu", "step", "dropped");
+        warn("cpu", "step", "second");
+
+        set_level(Level::Trace);
+        trace("mmu", "read_byte", "third");
+
+        assert_eq!(capture.lines(), vec![
+            "|INFO| cpu        | step       | first",
+            "|WARN| cpu        | step       | second",
+            "|TRCE| mmu        | read_byte  | third",
+        ]);
+    }
+
+    #[test]
+    fn test_subsystems() {
+        let capture = capture();
+        set_subsystems(&["cpu", "int"]);
+        info("cpu", "step", "first");
+        info("mmu", "read_byte", "dropped");
+        info("int", "service", "second");
+        assert!(!is_enabled(Level::Info, "gpu"));
+
+        set_subsystems(&[]);
+        info("gpu", "step", "third");
+        assert_eq!(capture.lines().len(), 3);
+    }
+
+    #[test]
+    fn test_level_from_name() {
+        assert_eq!(Level::from_name("warn"), Some(Level::Warn));
+        assert_eq!(Level::from_name("TRACE"), Some(Level::Trace));
+        assert_eq!(Level::from_name("verbose"), None);
+    }
 }
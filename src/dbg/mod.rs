@@ -1 +1,5 @@
+mod debugger;
+pub use self::debugger::{BreakReason, Debugger};
+
+pub mod disasm;
 pub mod log;
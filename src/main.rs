@@ -1,3 +1,5 @@
+use std::io::Write;
+
 use clap::{Arg, App};
 use minifb::{Key, Window, WindowOptions};
 
@@ -9,14 +11,29 @@ mod mem;
 mod sys;
 mod vid;
 
-fn app_gui() {
+// Keys of the keyboard mapped to each button of the joypad.
+const KEYMAP: [(mem::joypad::Button, &[Key]); 8] = [
+    (mem::joypad::Button::Right, &[Key::Right]),
+    (mem::joypad::Button::Left, &[Key::Left]),
+    (mem::joypad::Button::Up, &[Key::Up]),
+    (mem::joypad::Button::Down, &[Key::Down]),
+    (mem::joypad::Button::A, &[Key::X]),
+    (mem::joypad::Button::B, &[Key::Z]),
+    (mem::joypad::Button::Select, &[Key::Backspace, Key::LeftShift, Key::RightShift]),
+    (mem::joypad::Button::Start, &[Key::Enter]),
+];
+
+fn app_gui(sys: &mut sys::System) -> Result<(), sys::SystemError> {
     let win_w = vid::gpu::SCREEN_W;
     let win_h = vid::gpu::SCREEN_H;
     let mut buffer: Vec<u32> = vec![0; win_w * win_h];
-    let mut sys = sys::System::new();
 
 
-    let mut window = Window::new(".: RGB - GameBoy Emulator :. (ESC to exit)",
+    let title = match sys.mmu.cartridge().and_then(|cart| cart.header()) {
+        Some(header) => format!(".: RGB - {} :. (ESC to exit)", header.title()),
+        None => ".: RGB - GameBoy Emulator :. (ESC to exit)".to_string(),
+    };
+    let mut window = Window::new(&title,
             win_w, win_h, WindowOptions::default())
         .unwrap_or_else(|e| { panic!("{}", e); });
 
@@ -24,32 +41,185 @@ fn app_gui() {
     window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
+        // Buttons are sampled once per frame; released keys release their
+        // button.
+        let mut bits = 0x00;
+        for (button, keys) in KEYMAP.iter() {
+            if keys.iter().any(|key| window.is_key_down(*key)) {
+                bits |= button.bit();
+            }
+        }
+        sys.set_joypad_from_bits(bits);
+
+        let frame_end = sys.elapsed_cycles() + vid::gpu::FRAME_NCYCLES as u64;
+        while sys.elapsed_cycles() < frame_end {
+            sys.step()?;
+        }
+
         sys.mmu.gpu.copy_screen(&mut buffer[..]);
         window.update_with_buffer(&buffer, win_w, win_h).unwrap();
     }
+    return Ok(());
 }
 
-fn app_cli() {
-    let mut sys = sys::System::new();
-
+// Runs the emulation without a window.  At each break (see --break), the
+// CPU state and the next instruction are shown, and the user picks how to
+// resume.
+fn app_cli(sys: &mut sys::System) -> Result<(), sys::SystemError> {
     loop {
-        sys.step();
+        let reason = sys.run_until_break()?;
+        let (mnemo, _) = dbg::disasm::disassemble(&sys.mmu, sys.cpu.pc());
+        println!("break: {:?}", reason);
+        println!("{}", sys.dump_cpu_state());
+        println!("0x{:04x}: {}", sys.cpu.pc(), mnemo);
+
+        print!("(c)ontinue, (s)tep, (q)uit> ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => return Ok(()),
+            Ok(_) => {},
+        };
+        match line.trim() {
+            "q" => return Ok(()),
+            "s" => sys.debugger.set_stepping(true),
+            _ => sys.debugger.set_stepping(false),
+        };
     }
 }
 
+// Parses the address |s|, in hexadecimal with an optional "0x" prefix.
+fn parse_addr(s: &str) -> Option<u16> {
+    return u16::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+}
+
+// Reports the fatal error |msg|, and exits.  It bypasses the logger, so
+// that its level and filter can't hide the error.
+fn exit_with_error(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    std::process::exit(1);
+}
+
 fn main() {
     let matches = App::new("rgb")
         .version("0.1.0")
         .author("Samuel Gosselin")
+        .arg(Arg::with_name("rom")
+            .index(1)
+            .value_name("ROM")
+            .help("path to the cartridge ROM to run"))
         .arg(Arg::with_name("gui")
             .long("gui")
             .multiple(false)
             .help("start a gui version of the emulator"))
+        .arg(Arg::with_name("doctor-log")
+            .long("doctor-log")
+            .value_name("PATH")
+            .takes_value(true)
+            .help("write a GameBoy Doctor compatible log of the CPU state to PATH"))
+        .arg(Arg::with_name("trace-to")
+            .long("trace-to")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("write the CPU trace to FILE"))
+        .arg(Arg::with_name("trace-format")
+            .long("trace-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&["opcode", "blargg", "doctor"])
+            .help("format of the --trace-to lines (default: opcode)"))
+        .arg(Arg::with_name("break")
+            .long("break")
+            .value_name("ADDR")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("stop before executing the instruction at ADDR (hexadecimal), \
+                   and prompt to continue or step; ignored with --gui"))
+        .arg(Arg::with_name("skip-boot")
+            .long("skip-boot")
+            .alias("skip-bios")
+            .multiple(false)
+            .help("skip the BIOS and start executing the cartridge directly"))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .value_name("LEVEL")
+            .takes_value(true)
+            .possible_values(&["error", "warn", "info", "trace"])
+            .help("log the messages up to LEVEL (default: info)"))
+        .arg(Arg::with_name("log-filter")
+            .long("log-filter")
+            .value_name("SUBSYSTEMS")
+            .takes_value(true)
+            .help("only log the messages of the comma-separated SUBSYSTEMS (e.g., cpu,int)"))
         .get_matches();
 
-    if matches.is_present("gui") {
-        app_gui();
+    if let Some(level) = matches.value_of("log-level") {
+        dbg::log::set_level(dbg::log::Level::from_name(level).unwrap());
+    }
+
+    if let Some(subsystems) = matches.value_of("log-filter") {
+        let subsystems: Vec<&str> = subsystems.split(',').collect();
+        dbg::log::set_subsystems(&subsystems);
+    }
+
+    let mut sys = if matches.is_present("skip-boot") {
+        sys::System::new_post_bios()
     } else {
-        app_cli();
+        sys::System::new()
+    };
+
+    // Battery-backed saves live next to the ROM, e.g. tetris.gb -> tetris.sav.
+    let mut save_path = None;
+    if let Some(path) = matches.value_of("rom") {
+        sys.load_rom(std::path::Path::new(path))
+            .unwrap_or_else(|e| exit_with_error(&format!("failed to load '{}': {}", path, e)));
+
+        let path = std::path::Path::new(path).with_extension("sav");
+        sys.load_save(&path)
+            .unwrap_or_else(|e| exit_with_error(&format!("failed to load '{}': {}",
+                path.display(), e)));
+        save_path = Some(path);
+    }
+
+    if let Some(path) = matches.value_of("doctor-log") {
+        sys.enable_doctor_log(std::path::Path::new(path))
+            .unwrap_or_else(|e| exit_with_error(&format!("failed to create '{}': {}", path, e)));
+    }
+
+    if let Some(path) = matches.value_of("trace-to") {
+        let format = matches.value_of("trace-format")
+            .map_or(sys::TraceFormat::Opcode, |name| sys::TraceFormat::from_name(name).unwrap());
+        sys.enable_trace_to(std::path::Path::new(path), format)
+            .unwrap_or_else(|e| exit_with_error(&format!("failed to create '{}': {}", path, e)));
+    }
+
+    if let Some(addrs) = matches.values_of("break") {
+        for addr in addrs {
+            let addr = parse_addr(addr)
+                .unwrap_or_else(|| exit_with_error(&format!("invalid address '{}'", addr)));
+            sys.add_breakpoint(addr);
+        }
+    }
+
+    let res = if matches.is_present("gui") {
+        app_gui(&mut sys)
+    } else {
+        app_cli(&mut sys)
+    };
+
+    // The emulation is over, one way or another: persist the game's saves
+    // and the traces, which exiting would otherwise drop.
+    if let Err(e) = sys.flush_traces() {
+        eprintln!("error: failed to write the traces: {}", e);
+    }
+    if let Some(path) = save_path.as_ref() {
+        if let Err(e) = sys.save_sram(path) {
+            eprintln!("error: failed to write '{}': {}", path.display(), e);
+        }
+    }
+
+    if let Err(e) = res {
+        exit_with_error(&e.to_string());
     }
 }
@@ -0,0 +1,305 @@
+use std::error;
+use std::fmt;
+
+// Cartridge header:
+//
+// Every cartridge contains a header at 0x0100-0x014F, describing the
+// game (title, target hardware) and the cartridge hardware (memory bank
+// controller, ROM & RAM sizes).  It is protected by a checksum that the
+// BIOS verifies before handing over to the cartridge.
+//
+const TITLE_BEG_ADDR: usize = 0x0134;
+const TITLE_END_ADDR: usize = 0x0143;
+const CGB_FLAG_ADDR: usize = 0x0143;
+pub const CART_TYPE_ADDR: usize = 0x0147;
+const ROM_SIZE_ADDR: usize = 0x0148;
+pub const RAM_SIZE_ADDR: usize = 0x0149;
+const HEADER_CHECKSUM_ADDR: usize = 0x014d;
+
+/// Minimum size of a ROM; anything smaller can't hold the header.
+pub const HEADER_END_ADDR: usize = 0x0150;
+
+/// Enumerates the errors which can happen when parsing a cartridge.
+#[derive(Debug, PartialEq)]
+pub enum CartError {
+    TooSmall { need: usize, got: usize },
+    BadHeaderChecksum { expected: u8, got: u8 },
+}
+
+impl fmt::Display for CartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartError::TooSmall { need, got } => write!(f,
+                "rom is too small: need at least {} bytes, got {}", need, got),
+            CartError::BadHeaderChecksum { expected, got } => write!(f,
+                "bad header checksum: expected 0x{:02x}, got 0x{:02x}", expected, got),
+        }
+    }
+}
+
+impl error::Error for CartError {}
+
+/// Enumerates the memory bank controllers (MBC) found in cartridges.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MbcKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Unknown(u8),
+}
+
+impl MbcKind {
+    /// Returns the MBC found in cartridges of type |cart_type|.
+    pub fn from_cart_type(cart_type: u8) -> MbcKind {
+        return match cart_type {
+            0x00 => MbcKind::RomOnly,
+            0x01..=0x03 => MbcKind::Mbc1,
+            0x05..=0x06 => MbcKind::Mbc2,
+            0x0f..=0x13 => MbcKind::Mbc3,
+            0x19..=0x1e => MbcKind::Mbc5,
+            _ => MbcKind::Unknown(cart_type),
+        };
+    }
+}
+
+/// Returns |true| iff. cartridges of type |cart_type| have a battery,
+/// which keeps the external RAM (i.e., the saves) while powered off.
+pub fn has_battery_from_cart_type(cart_type: u8) -> bool {
+    return match cart_type {
+        0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x22 | 0xff => true,
+        _ => false,
+    };
+}
+
+/// Returns the size of the external RAM, in bytes, described by the
+/// header's RAM size |code|.
+pub fn ram_size_from_code(code: u8) -> usize {
+    return match code {
+        0x01 => 2 * 1024,
+        0x02 => 8 * 1024,
+        0x03 => 32 * 1024,
+        0x04 => 128 * 1024,
+        0x05 => 64 * 1024,
+        _ => 0,
+    };
+}
+
+/// Represents the header of a cartridge.
+#[derive(Debug)]
+pub struct CartridgeHeader {
+    title: String,
+    cgb_flag: u8,
+    cart_type: u8,
+    rom_size: u8,
+    ram_size: u8,
+    header_checksum: u8,
+}
+
+impl CartridgeHeader {
+    /// Parses the header of |rom|, and verifies its checksum.
+    pub fn from_rom(rom: &[u8]) -> Result<CartridgeHeader, CartError> {
+        if rom.len() < HEADER_END_ADDR {
+            return Err(CartError::TooSmall { need: HEADER_END_ADDR, got: rom.len() });
+        }
+
+        let expected = CartridgeHeader::checksum(rom);
+        let got = rom[HEADER_CHECKSUM_ADDR];
+        if expected != got {
+            return Err(CartError::BadHeaderChecksum { expected, got });
+        }
+
+        // The title is padded with zeros.  On CGB cartridges, its last byte
+        // is repurposed as the CGB flag.
+        let cgb_flag = rom[CGB_FLAG_ADDR];
+        let title_end = if cgb_flag & 0x80 != 0 { TITLE_END_ADDR } else { TITLE_END_ADDR + 1 };
+        let title = rom[TITLE_BEG_ADDR..title_end].iter()
+            .take_while(|c| **c != 0x00)
+            .map(|c| *c as char)
+            .collect();
+
+        return Ok(CartridgeHeader {
+            title,
+            cgb_flag,
+            cart_type: rom[CART_TYPE_ADDR],
+            rom_size: rom[ROM_SIZE_ADDR],
+            ram_size: rom[RAM_SIZE_ADDR],
+            header_checksum: got,
+        });
+    }
+
+    /// Computes the header checksum over 0x0134-0x014C, the way the BIOS
+    /// does.
+    pub fn checksum(rom: &[u8]) -> u8 {
+        return rom[TITLE_BEG_ADDR..HEADER_CHECKSUM_ADDR].iter()
+            .fold(0u8, |x, byte| x.wrapping_sub(*byte).wrapping_sub(1));
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn cart_type(&self) -> u8 {
+        self.cart_type
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.header_checksum
+    }
+
+    /// Returns |true| iff. the cartridge supports CGB features.
+    pub fn is_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    /// Returns the size of the ROM, in bytes.
+    pub fn rom_size(&self) -> usize {
+        (32 * 1024) << self.rom_size
+    }
+
+    /// Returns the size of the external RAM, in bytes.
+    pub fn ram_size(&self) -> usize {
+        ram_size_from_code(self.ram_size)
+    }
+
+    pub fn mbc_kind(&self) -> MbcKind {
+        MbcKind::from_cart_type(self.cart_type)
+    }
+
+    /// Returns |true| iff. the cartridge has a battery-backed RAM.
+    pub fn has_battery(&self) -> bool {
+        has_battery_from_cart_type(self.cart_type)
+    }
+}
+
+/// Represents a cartridge: its ROM, and its external RAM (sized from the
+/// header) which may be battery-backed.
+pub struct Cartridge {
+    header: Option<CartridgeHeader>,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    has_battery: bool,
+}
+
+impl Cartridge {
+    /// Creates a cartridge from |rom|.  The header isn't required to be
+    /// valid: the hardware doesn't check it past the BIOS.
+    pub fn new(rom: Vec<u8>) -> Cartridge {
+        let cart_type = rom.get(CART_TYPE_ADDR).copied().unwrap_or(0x00);
+        let ram_size = ram_size_from_code(rom.get(RAM_SIZE_ADDR).copied().unwrap_or(0x00));
+        return Cartridge {
+            header: CartridgeHeader::from_rom(&rom).ok(),
+            ram: vec![0x00; ram_size],
+            has_battery: has_battery_from_cart_type(cart_type),
+            rom,
+        };
+    }
+
+    /// Returns the header of the cartridge; |None| if it is invalid.
+    pub fn header(&self) -> Option<&CartridgeHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    /// Returns the external RAM if it is battery-backed, i.e. if it holds
+    /// saves which must persist; returns |None| otherwise.
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery && !self.ram.is_empty() {
+            return Some(&self.ram);
+        }
+        return None;
+    }
+
+    /// Restores the battery-backed external RAM from |data|; fails if the
+    /// cartridge has none, or if its size doesn't match.
+    pub fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        match self.battery_ram() {
+            None => return Err("the cartridge has no battery-backed ram".to_string()),
+            Some(ram) if ram.len() != data.len() => {
+                return Err(format!("expected {} bytes of ram, got {}", ram.len(), data.len()));
+            },
+            Some(_) => {},
+        };
+        self.ram.copy_from_slice(data);
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Builds a 32 KiB ROM with a valid header.
+    pub fn make_rom(title: &str, cart_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0x00; (32 * 1024) << rom_size];
+        for (i, c) in title.bytes().enumerate() {
+            rom[TITLE_BEG_ADDR + i] = c;
+        }
+        rom[CART_TYPE_ADDR] = cart_type;
+        rom[ROM_SIZE_ADDR] = rom_size;
+        rom[RAM_SIZE_ADDR] = ram_size;
+        rom[HEADER_CHECKSUM_ADDR] = CartridgeHeader::checksum(&rom);
+        return rom;
+    }
+
+    #[test]
+    fn test_from_rom() {
+        let rom = make_rom("TETRIS", 0x01, 0x01, 0x02);
+        let header = CartridgeHeader::from_rom(&rom).unwrap();
+        assert_eq!(header.title(), "TETRIS");
+        assert_eq!(header.cart_type(), 0x01);
+        assert_eq!(header.mbc_kind(), MbcKind::Mbc1);
+        assert_eq!(header.rom_size(), 64 * 1024);
+        assert_eq!(header.ram_size(), 8 * 1024);
+        assert_eq!(header.is_cgb(), false);
+        assert_eq!(header.has_battery(), false);
+    }
+
+    #[test]
+    fn test_has_battery() {
+        for cart_type in [0x03, 0x06, 0x0f, 0x10, 0x13, 0x1b, 0x1e].iter() {
+            assert!(has_battery_from_cart_type(*cart_type), "type=0x{:02x}", cart_type);
+        }
+        for cart_type in [0x00, 0x01, 0x02, 0x05, 0x11, 0x12, 0x19, 0x1a].iter() {
+            assert!(!has_battery_from_cart_type(*cart_type), "type=0x{:02x}", cart_type);
+        }
+    }
+
+    #[test]
+    fn test_from_rom_cgb() {
+        let mut rom = make_rom("POKEMON_GLDAAUE", 0x10, 0x00, 0x00);
+        rom[CGB_FLAG_ADDR] = 0x80;
+        rom[HEADER_CHECKSUM_ADDR] = CartridgeHeader::checksum(&rom);
+        let header = CartridgeHeader::from_rom(&rom).unwrap();
+        assert_eq!(header.title(), "POKEMON_GLDAAUE");
+        assert_eq!(header.mbc_kind(), MbcKind::Mbc3);
+        assert_eq!(header.is_cgb(), true);
+    }
+
+    #[test]
+    fn test_bad_header_checksum() {
+        let mut rom = make_rom("TETRIS", 0x00, 0x00, 0x00);
+        let expected = rom[HEADER_CHECKSUM_ADDR];
+        rom[HEADER_CHECKSUM_ADDR] = expected.wrapping_add(1);
+        assert_eq!(CartridgeHeader::from_rom(&rom).unwrap_err(),
+            CartError::BadHeaderChecksum { expected, got: expected.wrapping_add(1) });
+    }
+
+    #[test]
+    fn test_too_small() {
+        assert_eq!(CartridgeHeader::from_rom(&[0x00; 100]).unwrap_err(),
+            CartError::TooSmall { need: 0x150, got: 100 });
+    }
+}
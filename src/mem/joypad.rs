@@ -0,0 +1,228 @@
+use std::io;
+
+use crate::dbg::log;
+use crate::sys::state::{StateReader, StateWriter};
+
+// Joypad register (P1):
+//
+// The 8 buttons are wired as a 2x4 matrix.  Clearing bit 4 selects the
+// directions, and clearing bit 5 selects the actions; the lower 4 bits
+// then report the buttons of the selected groups, with 0 meaning
+// pressed.  The upper 2 bits are unused and read as 1.
+//
+const P1_ADDR: u16 = 0xff00;
+const P1_SELECT_DIRECTIONS: u8 = 1 << 4;
+const P1_SELECT_ACTIONS: u8 = 1 << 5;
+const P1_SELECT_MASK: u8 = P1_SELECT_DIRECTIONS | P1_SELECT_ACTIONS;
+
+/// Enumerates the buttons of the GameBoy.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// All the buttons, in P1 order.
+    pub const ALL: [Button; 8] = [
+        Button::Right, Button::Left, Button::Up, Button::Down,
+        Button::A, Button::B, Button::Select, Button::Start,
+    ];
+
+    /// Returns the bit of the button in a pressed mask: directions in the
+    /// lower nibble, actions in the upper nibble, in P1 order (i.e. Right
+    /// is bit 0, and Start is bit 7).
+    pub fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+/// Represents which buttons are pressed, regardless of the groups selected
+/// in P1.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ButtonState {
+    pressed: u8,
+}
+
+impl ButtonState {
+    /// Returns |true| iff. |button| is pressed.
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed & button.bit() != 0
+    }
+}
+
+/// Represents the joypad of the GameBoy.
+pub struct Joypad {
+    select: u8,
+    pressed: u8,
+}
+
+impl Joypad {
+    /// Creates an initialized Joypad, with no group selected.
+    pub fn new() -> Joypad {
+        return Joypad {
+            select: P1_SELECT_MASK,
+            pressed: 0x00,
+        };
+    }
+
+    /// Reads the joypad register at |addr|.
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        return match addr {
+            P1_ADDR => 0xc0 | self.select | self._get_lines(),
+            _ => panic!("address not owned by the joypad"),
+        };
+    }
+
+    /// Writes |val| into the joypad register at |addr|; returns |true| iff.
+    /// the joypad interrupt must be requested.
+    pub fn write_byte(&mut self, addr: u16, val: u8) -> bool {
+        return match addr {
+            P1_ADDR => {
+                let lines = self._get_lines();
+                self.select = val & P1_SELECT_MASK;
+                self._is_falling_edge(lines)
+            },
+            _ => panic!("address not owned by the joypad"),
+        };
+    }
+
+    /// Presses or releases |button|; returns |true| iff. the joypad
+    /// interrupt must be requested.
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let lines = self._get_lines();
+        let old_pressed = self.pressed;
+        if pressed {
+            self.pressed |= button.bit();
+        } else {
+            self.pressed &= !button.bit();
+        }
+
+        // The buttons are sampled every frame: only log the changes.
+        if self.pressed != old_pressed {
+            log::info("joypad", "set_button", &format!("button={:?} pressed={}",
+                button, pressed));
+        }
+
+        return self._is_falling_edge(lines);
+    }
+
+    /// Presses the buttons whose bit is set in |bits|, and releases the
+    /// others, at once (see |Button::bit| for the bit order); returns
+    /// |true| iff. the joypad interrupt must be requested.
+    pub fn set_buttons(&mut self, bits: u8) -> bool {
+        let lines = self._get_lines();
+        if self.pressed != bits {
+            log::info("joypad", "set_buttons", &format!("pressed=0x{:02x}", bits));
+        }
+        self.pressed = bits;
+        return self._is_falling_edge(lines);
+    }
+
+    /// Returns which buttons are pressed.
+    pub fn button_state(&self) -> ButtonState {
+        ButtonState { pressed: self.pressed }
+    }
+
+    /// Serializes the joypad into |w|.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.select);
+        w.write_u8(self.pressed);
+    }
+
+    /// Restores the joypad from |r|.
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.select = r.read_u8()?;
+        self.pressed = r.read_u8()?;
+        return Ok(());
+    }
+
+    // Returns the lower 4 bits of P1: the buttons of the selected groups,
+    // with 0 meaning pressed.
+    fn _get_lines(&self) -> u8 {
+        let mut pressed = 0x00;
+        if self.select & P1_SELECT_DIRECTIONS == 0 {
+            pressed |= self.pressed & 0x0f;
+        }
+        if self.select & P1_SELECT_ACTIONS == 0 {
+            pressed |= self.pressed >> 4;
+        }
+        return !pressed & 0x0f;
+    }
+
+    // Returns |true| iff. a line went from high to low since |lines|.
+    fn _is_falling_edge(&self, lines: u8) -> bool {
+        lines & !self._get_lines() != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_selection() {
+        let mut joypad = Joypad::new();
+        assert_eq!(joypad.read_byte(P1_ADDR), 0xff);
+        assert_eq!(joypad.set_button(Button::A, true), false);
+        assert_eq!(joypad.read_byte(P1_ADDR), 0xff);
+    }
+
+    #[test]
+    fn test_directions() {
+        let mut joypad = Joypad::new();
+        joypad.write_byte(P1_ADDR, 0x20);
+        assert_eq!(joypad.read_byte(P1_ADDR), 0xef);
+
+        assert_eq!(joypad.set_button(Button::Up, true), true);
+        assert_eq!(joypad.set_button(Button::A, true), false);
+        assert_eq!(joypad.read_byte(P1_ADDR), 0xeb);
+        joypad.set_button(Button::Up, false);
+        assert_eq!(joypad.read_byte(P1_ADDR), 0xef);
+    }
+
+    #[test]
+    fn test_actions() {
+        let mut joypad = Joypad::new();
+        joypad.write_byte(P1_ADDR, 0x10);
+        assert_eq!(joypad.read_byte(P1_ADDR), 0xdf);
+
+        assert_eq!(joypad.set_button(Button::Start, true), true);
+        assert_eq!(joypad.set_button(Button::Down, true), false);
+        assert_eq!(joypad.read_byte(P1_ADDR), 0xd7);
+        assert_eq!(joypad.set_button(Button::B, true), true);
+        assert_eq!(joypad.read_byte(P1_ADDR), 0xd5);
+
+        // Both groups can be selected at once.
+        joypad.write_byte(P1_ADDR, 0x00);
+        assert_eq!(joypad.read_byte(P1_ADDR), 0xc5);
+    }
+
+    #[test]
+    fn test_button_state() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Up, true);
+        joypad.set_button(Button::Start, true);
+
+        // The state doesn't depend on the selected groups.
+        let state = joypad.button_state();
+        for button in Button::ALL.iter() {
+            let expected = *button == Button::Up || *button == Button::Start;
+            assert_eq!(state.is_pressed(*button), expected, "{:?}", button);
+        }
+    }
+
+    #[test]
+    fn test_select_falling_edge() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Right, true);
+        assert_eq!(joypad.write_byte(P1_ADDR, 0x10), false);
+        assert_eq!(joypad.write_byte(P1_ADDR, 0x20), true);
+    }
+}
@@ -0,0 +1,350 @@
+use std::io;
+
+use crate::sys::state::{StateReader, StateWriter};
+
+// Memory Bank Controllers:
+//
+// Cartridges larger than 32 KiB contain a memory bank controller (MBC),
+// which selects the ROM bank mapped at 0x4000-0x7FFF, and the external
+// RAM bank mapped at 0xA000-0xBFFF.  The MBC registers are written
+// through the ROM region (0x0000-0x7FFF), which is otherwise read-only.
+//
+const ROM_BANK_LEN: usize = 0x4000;
+const RAM_BANK_LEN: usize = 0x2000;
+const ERAM_BEG_ADDR: u16 = 0xa000;
+
+/// Represents the memory bank controller of a cartridge.
+pub trait Mbc {
+    /// Returns the offset in the ROM backing |addr| (0x0000-0x7FFF).
+    fn rom_offset(&self, addr: u16) -> usize;
+
+    /// Returns the offset in the external RAM backing |addr|
+    /// (0xA000-0xBFFF), or |None| if the external RAM is disabled.
+    fn ram_offset(&self, addr: u16) -> Option<usize>;
+
+    /// Handles a write into the ROM region (0x0000-0x7FFF).
+    fn write_rom(&mut self, addr: u16, val: u8);
+
+    /// Serializes the bank registers into |w|.
+    fn save_state(&self, w: &mut StateWriter);
+
+    /// Restores the bank registers from |r|.
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()>;
+}
+
+/// Cartridges without an MBC: the 32 KiB of ROM are mapped as-is.
+pub struct NoMbc;
+
+impl Mbc for NoMbc {
+    fn rom_offset(&self, addr: u16) -> usize {
+        addr as usize
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        Some((addr - ERAM_BEG_ADDR) as usize)
+    }
+
+    fn write_rom(&mut self, _addr: u16, _val: u8) {
+        /* NOP */
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {
+        /* NOP */
+    }
+
+    fn load_state(&mut self, _r: &mut StateReader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// MBC1; supports up to 2 MiB of ROM (125 banks).
+///
+/// The ROM bank is made of 7 bits: the lower 5 bits are written through
+/// 0x2000-0x3FFF, and the upper 2 bits through 0x4000-0x5FFF.  Writes to
+/// 0x6000-0x7FFF select the banking mode: in mode 1, the upper 2 bits
+/// also select the bank mapped at 0x0000-0x3FFF, and the external RAM
+/// bank.  The external RAM is disabled until 0x0A is written through
+/// 0x0000-0x1FFF.
+pub struct Mbc1 {
+    rom_nbanks: usize,
+    ram_nbanks: usize,
+    is_ram_enabled: bool,
+    rom_bank_lo: u8,
+    bank_hi: u8,
+    mode: u8,
+}
+
+impl Mbc1 {
+    pub fn new(rom_nbanks: usize, ram_nbanks: usize) -> Mbc1 {
+        return Mbc1 {
+            rom_nbanks,
+            ram_nbanks,
+            is_ram_enabled: false,
+            rom_bank_lo: 1,
+            bank_hi: 0,
+            mode: 0,
+        };
+    }
+
+    fn _rom_bank0(&self) -> usize {
+        if self.mode == 1 { (self.bank_hi as usize) << 5 } else { 0 }
+    }
+
+    fn _rom_bankx(&self) -> usize {
+        ((self.bank_hi as usize) << 5) | (self.rom_bank_lo as usize)
+    }
+
+    fn _ram_bank(&self) -> usize {
+        if self.mode == 1 { self.bank_hi as usize } else { 0 }
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        let (bank, offset) = match addr {
+            0x0000..=0x3fff => (self._rom_bank0(), addr as usize),
+            _ => (self._rom_bankx(), (addr - 0x4000) as usize),
+        };
+        return (bank % self.rom_nbanks) * ROM_BANK_LEN + offset;
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.is_ram_enabled {
+            return None;
+        }
+        let bank = self._ram_bank() % std::cmp::max(self.ram_nbanks, 1);
+        return Some(bank * RAM_BANK_LEN + (addr - ERAM_BEG_ADDR) as usize);
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.is_ram_enabled = val & 0x0f == 0x0a,
+            0x2000..=0x3fff => {
+                // Bank 0 can't be selected at 0x4000-0x7FFF: writing 0
+                // selects bank 1.  Since only the lower 5 bits are
+                // checked, banks 0x20, 0x40 & 0x60 map to the next bank.
+                self.rom_bank_lo = val & 0x1f;
+                if self.rom_bank_lo == 0 {
+                    self.rom_bank_lo = 1;
+                }
+            },
+            0x4000..=0x5fff => self.bank_hi = val & 0x03,
+            0x6000..=0x7fff => self.mode = val & 0x01,
+            _ => { /* NOP */ },
+        };
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.is_ram_enabled);
+        w.write_u8(self.rom_bank_lo);
+        w.write_u8(self.bank_hi);
+        w.write_u8(self.mode);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.is_ram_enabled = r.read_bool()?;
+        self.rom_bank_lo = r.read_u8()?;
+        self.bank_hi = r.read_u8()?;
+        self.mode = r.read_u8()?;
+        return Ok(());
+    }
+}
+
+/// MBC5; supports up to 8 MiB of ROM (512 banks), and 128 KiB of RAM (16
+/// banks).
+///
+/// The ROM bank is made of 9 bits: the lower 8 bits are written through
+/// 0x2000-0x2FFF, and the upper bit through 0x3000-0x3FFF.  Unlike MBC1,
+/// bank 0 can be mapped at 0x4000-0x7FFF.  The RAM bank is written
+/// through 0x4000-0x5FFF.  The external RAM is disabled until 0x0A is
+/// written through 0x0000-0x1FFF.
+pub struct Mbc5 {
+    rom_nbanks: usize,
+    ram_nbanks: usize,
+    is_ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+}
+
+impl Mbc5 {
+    pub fn new(rom_nbanks: usize, ram_nbanks: usize) -> Mbc5 {
+        return Mbc5 {
+            rom_nbanks,
+            ram_nbanks,
+            is_ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        };
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn rom_offset(&self, addr: u16) -> usize {
+        let (bank, offset) = match addr {
+            0x0000..=0x3fff => (0, addr as usize),
+            _ => (self.rom_bank as usize, (addr - 0x4000) as usize),
+        };
+        return (bank % self.rom_nbanks) * ROM_BANK_LEN + offset;
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.is_ram_enabled {
+            return None;
+        }
+        let bank = self.ram_bank as usize % std::cmp::max(self.ram_nbanks, 1);
+        return Some(bank * RAM_BANK_LEN + (addr - ERAM_BEG_ADDR) as usize);
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.is_ram_enabled = val & 0x0f == 0x0a,
+            0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | val as u16,
+            0x3000..=0x3fff => {
+                self.rom_bank = (self.rom_bank & 0x0ff) | ((val as u16 & 0x01) << 8);
+            },
+            0x4000..=0x5fff => self.ram_bank = val & 0x0f,
+            _ => { /* NOP */ },
+        };
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.is_ram_enabled);
+        w.write_u16(self.rom_bank);
+        w.write_u8(self.ram_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.is_ram_enabled = r.read_bool()?;
+        self.rom_bank = r.read_u16()?;
+        self.ram_bank = r.read_u8()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_mbc() {
+        let mut mbc = NoMbc;
+        mbc.write_rom(0x2000, 0x02);
+        assert_eq!(mbc.rom_offset(0x0100), 0x0100);
+        assert_eq!(mbc.rom_offset(0x4000), 0x4000);
+        assert_eq!(mbc.ram_offset(0xa010), Some(0x0010));
+    }
+
+    #[test]
+    fn test_mbc1_rom_bank() {
+        let mut mbc = Mbc1::new(128, 4);
+        assert_eq!(mbc.rom_offset(0x4000), 0x4000);
+
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.rom_offset(0x0000), 0x0000);
+        assert_eq!(mbc.rom_offset(0x4000), 5 * ROM_BANK_LEN);
+        assert_eq!(mbc.rom_offset(0x7fff), 6 * ROM_BANK_LEN - 1);
+
+        // Only the lower 5 bits are used.
+        mbc.write_rom(0x3fff, 0xe3);
+        assert_eq!(mbc.rom_offset(0x4000), 3 * ROM_BANK_LEN);
+
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.rom_offset(0x4000), 0x23 * ROM_BANK_LEN);
+    }
+
+    #[test]
+    fn test_mbc1_bank0_remap() {
+        let mut mbc = Mbc1::new(128, 4);
+        for bank_hi in 0..4 {
+            mbc.write_rom(0x4000, bank_hi);
+            mbc.write_rom(0x2000, 0x00);
+            let bank = ((bank_hi as usize) << 5) + 1;
+            assert_eq!(mbc.rom_offset(0x4000), bank * ROM_BANK_LEN);
+        }
+    }
+
+    #[test]
+    fn test_mbc1_mode() {
+        let mut mbc = Mbc1::new(128, 4);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.rom_offset(0x0000), 0x0000);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.rom_offset(0x0000), 0x40 * ROM_BANK_LEN);
+        mbc.write_rom(0x6000, 0x00);
+        assert_eq!(mbc.rom_offset(0x0000), 0x0000);
+    }
+
+    #[test]
+    fn test_mbc1_ram() {
+        let mut mbc = Mbc1::new(128, 4);
+        assert_eq!(mbc.ram_offset(0xa000), None);
+        mbc.write_rom(0x0000, 0x0a);
+        assert_eq!(mbc.ram_offset(0xa000), Some(0x0000));
+        assert_eq!(mbc.ram_offset(0xbfff), Some(RAM_BANK_LEN - 1));
+
+        // The RAM bank is only selected in mode 1.
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.ram_offset(0xa000), Some(0x0000));
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.ram_offset(0xa000), Some(2 * RAM_BANK_LEN));
+
+        mbc.write_rom(0x1fff, 0x00);
+        assert_eq!(mbc.ram_offset(0xa000), None);
+    }
+
+    #[test]
+    fn test_mbc1_state() {
+        let mut mbc = Mbc1::new(128, 4);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x6000, 0x01);
+        let mut data = Vec::new();
+        let mut w = StateWriter::new(&mut data);
+        mbc.save_state(&mut w);
+        w.finish().unwrap();
+
+        let mut loaded = Mbc1::new(128, 4);
+        loaded.load_state(&mut StateReader::new(&mut &data[..]).unwrap()).unwrap();
+        assert_eq!(loaded.rom_offset(0x4000), 0x25 * ROM_BANK_LEN);
+        assert_eq!(loaded.ram_offset(0xa000), Some(RAM_BANK_LEN));
+    }
+
+    #[test]
+    fn test_mbc1_wraps_around_rom() {
+        let mut mbc = Mbc1::new(4, 0);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.rom_offset(0x4000), ROM_BANK_LEN);
+    }
+
+    #[test]
+    fn test_mbc5_rom_bank() {
+        let mut mbc = Mbc5::new(512, 16);
+        assert_eq!(mbc.rom_offset(0x4000), ROM_BANK_LEN);
+
+        mbc.write_rom(0x2000, 0x00);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.rom_offset(0x0000), 0x0000);
+        assert_eq!(mbc.rom_offset(0x4000), 0x100 * ROM_BANK_LEN);
+        assert_eq!(mbc.rom_offset(0x7fff), 0x101 * ROM_BANK_LEN - 1);
+
+        mbc.write_rom(0x2fff, 0x42);
+        assert_eq!(mbc.rom_offset(0x4000), 0x142 * ROM_BANK_LEN);
+
+        // Bank 0 can be mapped at 0x4000-0x7FFF.
+        mbc.write_rom(0x3fff, 0x00);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), 0x0000);
+    }
+
+    #[test]
+    fn test_mbc5_ram() {
+        let mut mbc = Mbc5::new(512, 16);
+        assert_eq!(mbc.ram_offset(0xa000), None);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, 0x0f);
+        assert_eq!(mbc.ram_offset(0xa000), Some(15 * RAM_BANK_LEN));
+        mbc.write_rom(0x5fff, 0x13);
+        assert_eq!(mbc.ram_offset(0xbfff), Some(4 * RAM_BANK_LEN - 1));
+    }
+}
@@ -1,6 +1,14 @@
+use std::collections::HashSet;
+use std::io;
+
 use crate::dbg::log;
+use crate::sys::state::{StateReader, StateWriter};
 use crate::vid::{Gpu};
 use super::bios::{BIOS};
+use super::cart::{CART_TYPE_ADDR, Cartridge, MbcKind};
+use super::joypad::{Button, Joypad};
+use super::mbc::{Mbc, Mbc1, Mbc5, NoMbc};
+use super::timer::Timer;
 
 // BIOS:
 //
@@ -11,10 +19,42 @@ use super::bios::{BIOS};
 const BIOS_BEG_ADDR: u16 = 0x0000;
 const BIOS_END_ADDR: u16 = 0x00FF;
 
+// Writing a non-zero value to this register unmaps the BIOS; it is the
+// last thing the BIOS does before jumping to the cartridge.
+const BIOS_UNMAP_ADDR: u16 = 0xff50;
 
+// Cartridge ROM:
+//
+// The first 16 KiB of the cartridge ROM (bank 0) are always mapped at
+// 0x0000-0x3FFF; the BIOS shadows its first 256 bytes until it is
+// unmapped.  The next 16 KiB (0x4000-0x7FFF) map a switchable bank,
+// selected by the cartridge's MBC (see src/mem/mbc.rs).
+//
+const ROM0_BEG_ADDR: u16 = 0x0000;
+const ROM0_END_ADDR: u16 = 0x3fff;
+const ROMX_BEG_ADDR: u16 = 0x4000;
+const ROMX_END_ADDR: u16 = 0x7fff;
+const ROM_BANK_LEN: usize = 0x4000;
+
+// Video RAM:
+//
+// This region holds the tile data and the tile maps used by the GPU to
+// render the background, the window, and the sprites.  It is owned by
+// the GPU.
+//
 const VRAM_BEG_ADDR: u16 = 0x8000;
 const VRAM_END_ADDR: u16 = 0x9fff;
-const VRAM_LEN:usize = (VRAM_END_ADDR - VRAM_BEG_ADDR + 1) as usize;
+
+// External RAM:
+//
+// Cartridges may contain RAM, often battery-backed to hold saves.  It
+// is sized from the cartridge header, and mapped at 0xA000-0xBFFF by
+// 8 KiB banks, selected by the cartridge's MBC.  Reads return 0xFF when
+// the RAM is disabled or missing.
+//
+const ERAM_BEG_ADDR: u16 = 0xa000;
+const ERAM_END_ADDR: u16 = 0xbfff;
+const ERAM_BANK_LEN: usize = 0x2000;
 
 // Working RAM:
 //
@@ -36,8 +76,81 @@ const RRAM_BEG_ADDR: u16 = 0xe000;
 const RRAM_END_ADDR: u16 = 0xfdff;
 const RRAM_LEN: usize = (RRAM_END_ADDR - RRAM_BEG_ADDR + 1) as usize;
 
+// Object Attribute Memory:
+//
+// This region holds the attributes (position, tile, flags) of the 40
+// sprites.  It is owned by the GPU.
+//
+const OAM_BEG_ADDR: u16 = 0xfe00;
+const OAM_END_ADDR: u16 = 0xfe9f;
+
+// I/O registers:
+//
+// This region holds the registers of the I/O devices (joypad, serial,
+// timer, sound, GPU).  Accesses are dispatched to the device owning the
+// register; registers of devices which aren't emulated yet are backed by
+// a plain array, so that they read back what was written.
+//
+const IO_BEG_ADDR: u16 = 0xff00;
+const IO_END_ADDR: u16 = 0xff7f;
+const IO_LEN: usize = (IO_END_ADDR - IO_BEG_ADDR + 1) as usize;
+
+// Joypad:
+//
+// The P1 register selects a group of buttons, and reports which of its
+// buttons are pressed.  It is owned by the joypad.
+//
+const JOYPAD_ADDR: u16 = 0xff00;
+
+// Timer:
+//
+// The DIV, TIMA, TMA and TAC registers.  They are owned by the timer.
+//
+const TIMER_BEG_ADDR: u16 = 0xff04;
+const TIMER_END_ADDR: u16 = 0xff07;
+
+// Interrupt flags:
+//
+// The IF register holds the interrupts requested by the devices; the IE
+// register, the interrupts enabled by the game.  An interrupt is pending
+// when its bit is set in both.  Only the lower 5 bits are used, the upper
+// 3 bits of IF read as 1.
+//
+const IF_ADDR: u16 = 0xff0f;
+const IE_ADDR: u16 = 0xffff;
+
+// LCD registers:
+//
+// These registers configure the GPU (control, status, scrolling,
+// palettes).  They are owned by the GPU.
+//
+const LCD_REGS_BEG_ADDR: u16 = 0xff40;
+const LCD_REGS_END_ADDR: u16 = 0xff4b;
+#[cfg(test)]
+const LCDC_ADDR: u16 = 0xff40;
+#[cfg(test)]
+const LYC_ADDR: u16 = 0xff45;
+
+// OAM DMA:
+//
+// Writing the DMA register copies 160 bytes from |val| << 8 into OAM.
+// The transfer is instantaneous here; on hardware, it takes 160 machine
+// cycles, during which the CPU can only access the Zero RAM.
+//
+const DMA_ADDR: u16 = 0xff46;
+const DMA_LEN: usize = (OAM_END_ADDR - OAM_BEG_ADDR + 1) as usize;
+
+// LCD Y-coordinate:
+//
+// The LY register reports the scanline being drawn; it is owned by the
+// GPU.  It can be pinned to a fixed value, which is what GameBoy Doctor
+// expects (0x90) since it does not emulate the PPU.
+//
+const LY_ADDR: u16 = 0xff44;
+
 // Zero RAM:
 //
+// Also known as High RAM (HRAM).
 // Originally intended to be used as stack space, it is also used for
 // fast memory access since some instructions operating in the 0xFF00
 // to 0xFFFF range (e.g., LD (C), A) are fsaster than typical LD instrs.
@@ -46,6 +159,46 @@ const ZRAM_BEG_ADDR: u16 = 0xff80;
 const ZRAM_END_ADDR: u16 = 0xfffe;
 const ZRAM_LEN: usize = (ZRAM_END_ADDR - ZRAM_BEG_ADDR + 1) as usize;
 
+/// Enumerates the interrupts, in priority order.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    const ALL: [Interrupt; 5] = [Interrupt::VBlank, Interrupt::LcdStat,
+        Interrupt::Timer, Interrupt::Serial, Interrupt::Joypad];
+
+    /// Returns the bit of the interrupt in the IE & IF registers.
+    pub fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+
+    /// Returns the address of the interrupt handler.
+    pub fn vector(&self) -> u16 {
+        0x0040 + (*self as u16) * 8
+    }
+}
+
+/// Represents a write to a watched address.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+// Identifies the RAM arrays owned by the MMU, for block copies.
+#[derive(Copy, Clone, PartialEq)]
+enum Ram {
+    Wram,
+    Zram,
+}
+
 /// Represents the memory interconnect of the GameBoy.
 ///
 /// In order to simplify the design, the memory interconnect (here, MMU) contains the
@@ -58,11 +211,24 @@ const ZRAM_LEN: usize = (ZRAM_END_ADDR - ZRAM_BEG_ADDR + 1) as usize;
 ///
 pub struct Mmu {
     is_bios_mapped: bool,
-    vram: [u8; VRAM_LEN],
+    cart: Option<Cartridge>,
+    mbc: Box<dyn Mbc>,
     wram: [u8; WRAM_LEN],
     zram: [u8; ZRAM_LEN],
+    io: [u8; IO_LEN],
+    int_enable: u8,
+    int_flag: u8,
+    dma: u8,
+    pinned_ly: Option<u8>,
+    watchpoints: HashSet<u16>,
+    watch_hit: Option<WatchHit>,
+    open_bus: u8,
+    #[cfg(test)]
+    last_write: Option<(u16, u8)>,
 
     pub gpu: Gpu,
+    pub timer: Timer,
+    pub joypad: Joypad,
 }
 
 impl Mmu {
@@ -70,13 +236,57 @@ impl Mmu {
     pub fn new() -> Mmu {
         return Mmu {
             is_bios_mapped: true,
-            vram: [0x00; VRAM_LEN],
+            cart: None,
+            mbc: Box::new(NoMbc),
             wram: [0x00; WRAM_LEN],
             zram: [0x00; ZRAM_LEN],
+            io: [0x00; IO_LEN],
+            int_enable: 0x00,
+            int_flag: 0x00,
+            dma: 0x00,
+            pinned_ly: None,
+            watchpoints: HashSet::new(),
+            watch_hit: None,
+            open_bus: 0x00,
+            #[cfg(test)]
+            last_write: None,
             gpu: Gpu::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
         };
     }
 
+    /// Inserts the cartridge |rom| into the memory.
+    pub fn load_rom(&mut self, rom: Vec<u8>) {
+        let cart = Cartridge::new(rom);
+        let cart_type = cart.rom().get(CART_TYPE_ADDR).copied().unwrap_or(0x00);
+        let rom_nbanks = std::cmp::max(cart.rom().len() / ROM_BANK_LEN, 2);
+        let ram_nbanks = cart.ram().len() / ERAM_BANK_LEN;
+        self.mbc = match MbcKind::from_cart_type(cart_type) {
+            MbcKind::RomOnly => Box::new(NoMbc),
+            MbcKind::Mbc1 => Box::new(Mbc1::new(rom_nbanks, ram_nbanks)),
+            MbcKind::Mbc5 => Box::new(Mbc5::new(rom_nbanks, ram_nbanks)),
+            kind => {
+                // Only the first 32 KiB of the ROM are reachable; the game
+                // is unlikely to run past its boot.
+                log::warn("mmu", "load_rom", &format!(
+                    "unsupported mbc {:?}: the rom is mapped without banking", kind));
+                Box::new(NoMbc)
+            },
+        };
+        self.cart = Some(cart);
+    }
+
+    /// Returns the inserted cartridge; |None| if no ROM is loaded.
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.cart.as_ref()
+    }
+
+    /// Returns the inserted cartridge, e.g. to restore its battery RAM.
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.cart.as_mut()
+    }
+
     /// Returns |true| iff. the BIOS is mapped.
     pub fn is_bios_mapped(&self) -> bool {
         self.is_bios_mapped
@@ -87,15 +297,84 @@ impl Mmu {
         self.is_bios_mapped = false;
     }
 
-    /// Reads a word from memory at |addr|; returns 0x00 if the
-    /// memory region is unmapped.
+    /// Requests the |int| interrupt, by setting its bit in IF.
+    pub fn request_interrupt(&mut self, int: Interrupt) {
+        self.int_flag |= int.bit();
+    }
+
+    /// Returns the highest priority interrupt both requested and enabled.
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.int_enable & self.int_flag;
+        return Interrupt::ALL.iter().copied()
+            .find(|int| pending & int.bit() != 0);
+    }
+
+    /// Acknowledges the |int| interrupt, by clearing its bit in IF.
+    pub fn ack_interrupt(&mut self, int: Interrupt) {
+        self.int_flag &= !int.bit();
+    }
+
+    /// Steps the devices owned by the MMU for a certain number of cycles,
+    /// and requests the interrupts they raised.
+    pub fn step(&mut self, ncycles: usize) {
+        self.gpu.step(ncycles);
+        self.int_flag |= self.gpu.take_interrupts();
+
+        if self.timer.step(ncycles) {
+            self.request_interrupt(Interrupt::Timer);
+        }
+    }
+
+    /// Presses or releases |button|, and requests the joypad interrupt if
+    /// a selected line goes low.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_button(button, pressed) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    /// Presses the buttons whose bit is set in |bits| and releases the
+    /// others, at once; requests the joypad interrupt if a line went low.
+    pub fn set_buttons(&mut self, bits: u8) {
+        if self.joypad.set_buttons(bits) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    /// Makes reads of the LY register always return |val|.
+    pub fn pin_ly(&mut self, val: u8) {
+        self.pinned_ly = Some(val);
+    }
+
+    /// Sets the value returned when reading an unmapped address.
+    ///
+    /// On hardware, the data bus floats when nothing drives it: this is
+    /// the case of the cartridge ROM (0x0000-0x7FFF) when no cartridge is
+    /// inserted, and of the unusable region (0xFEA0-0xFEFF).  Defaults to
+    /// 0x00.
+    pub fn set_open_bus_value(&mut self, val: u8) {
+        self.open_bus = val;
+    }
+
+    /// Reads a word from memory at |addr|; returns the open-bus value
+    /// (0x00 by default) if the memory region is unmapped.
     pub fn read_byte(&self, addr: u16) -> u8 {
         let res = match (self.is_bios_mapped, addr) {
             (true, BIOS_BEG_ADDR..=BIOS_END_ADDR) => {
                 BIOS[(addr - BIOS_BEG_ADDR) as usize]
             },
-            (_, VRAM_BEG_ADDR..=VRAM_END_ADDR) => {
-                self.vram[(addr - VRAM_BEG_ADDR) as usize]
+            (_, ROM0_BEG_ADDR..=ROM0_END_ADDR) |
+            (_, ROMX_BEG_ADDR..=ROMX_END_ADDR) => {
+                self._read_rom(self.mbc.rom_offset(addr))
+            },
+            (_, VRAM_BEG_ADDR..=VRAM_END_ADDR) |
+            (_, OAM_BEG_ADDR..=OAM_END_ADDR) => {
+                self.gpu.read_byte(addr)
+            },
+            (_, ERAM_BEG_ADDR..=ERAM_END_ADDR) => {
+                self.mbc.ram_offset(addr)
+                    .and_then(|offset| self.cart.as_ref()?.ram().get(offset).copied())
+                    .unwrap_or(0xff)
             },
             (_, WRAM_BEG_ADDR..=WRAM_END_ADDR) => {
                 self.wram[(addr - WRAM_BEG_ADDR) as usize]
@@ -103,24 +382,54 @@ impl Mmu {
             (_, RRAM_BEG_ADDR..=RRAM_END_ADDR) => {
                 self.wram[(addr - RRAM_BEG_ADDR) as usize]
             },
+            (_, IO_BEG_ADDR..=IO_END_ADDR) => {
+                self._read_io(addr)
+            },
             (_, ZRAM_BEG_ADDR..=ZRAM_END_ADDR) => {
                 self.zram[(addr - ZRAM_BEG_ADDR) as usize]
             },
+            (_, IE_ADDR) => {
+                self.int_enable
+            },
             _ => {
                 // The GameBoy returns 0x00 when nothing can be read at
                 // a specific address.  This manifests itself when the
                 // device boots without a cartridge: the NINTENDO logo
                 // is entirely black.
-                0x00
+                self.open_bus
             },
         };
 
-        log::info("mmu", "read_byte", &format!("addr=0x{:04x} res=0x{:02x}",
-            addr, res));
+        if log::is_enabled(log::Level::Trace, "mmu") {
+            log::trace("mmu", "read_byte", &format!("addr=0x{:04x} res=0x{:02x}",
+                addr, res));
+        }
 
         return res;
     }
 
+    // Reads the I/O register at |addr|, from the device owning it.
+    fn _read_io(&self, addr: u16) -> u8 {
+        return match addr {
+            JOYPAD_ADDR => self.joypad.read_byte(addr),
+            TIMER_BEG_ADDR..=TIMER_END_ADDR => self.timer.read_byte(addr),
+            IF_ADDR => self.int_flag | 0xe0,
+            DMA_ADDR => self.dma,
+            LY_ADDR if self.pinned_ly.is_some() => self.pinned_ly.unwrap(),
+            LCD_REGS_BEG_ADDR..=LCD_REGS_END_ADDR => self.gpu.read_byte(addr),
+            _ => self.io[(addr - IO_BEG_ADDR) as usize],
+        };
+    }
+
+    // Reads the byte at |offset| in the cartridge ROM; returns the open-bus
+    // value past the end of the ROM.
+    fn _read_rom(&self, offset: usize) -> u8 {
+        return match self.cart.as_ref().and_then(|cart| cart.rom().get(offset)) {
+            Some(val) => *val,
+            None => self.open_bus,
+        };
+    }
+
     /// Reads a word from memory at |addr|; returns 0x0000 if the
     /// memory region is unmapped.
     pub fn read_word(&self, addr: u16) -> u16 {
@@ -130,11 +439,102 @@ impl Mmu {
         return (msb << 8) | lsb;
     }
 
+    /// Returns the address and value of the most recent |write_byte|.
+    #[cfg(test)]
+    pub fn last_write(&self) -> Option<(u16, u8)> {
+        self.last_write
+    }
+
+    /// Serializes the memory and the devices into |w|.  The cartridge ROM
+    /// isn't part of the state, and must be loaded separately.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.is_bios_mapped);
+        self.mbc.save_state(w);
+        w.write_bytes(self.cart.as_ref().map_or(&[], |cart| cart.ram()));
+        w.write_bytes(&self.wram);
+        w.write_bytes(&self.zram);
+        w.write_bytes(&self.io);
+        w.write_u8(self.int_enable);
+        w.write_u8(self.int_flag);
+        w.write_u8(self.dma);
+        self.gpu.save_state(w);
+        self.timer.save_state(w);
+        self.joypad.save_state(w);
+    }
+
+    /// Restores the memory and the devices from |r|; the cartridge the
+    /// state was saved with must be loaded.
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.is_bios_mapped = r.read_bool()?;
+        self.mbc.load_state(r)?;
+        r.read_bytes_into(self.cart.as_mut().map_or(&mut [], |cart| cart.ram_mut()))?;
+        r.read_bytes_into(&mut self.wram)?;
+        r.read_bytes_into(&mut self.zram)?;
+        r.read_bytes_into(&mut self.io)?;
+        self.int_enable = r.read_u8()?;
+        self.int_flag = r.read_u8()?;
+        self.dma = r.read_u8()?;
+        self.gpu.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.joypad.load_state(r)?;
+        return Ok(());
+    }
+
+    /// Adds a watchpoint at |addr|: writes to it are recorded, see
+    /// |take_watch_hit|.
+    pub fn add_watchpoint(&mut self, addr: u16) {
+        self.watchpoints.insert(addr);
+    }
+
+    /// Removes the watchpoint at |addr|, if any.
+    pub fn remove_watchpoint(&mut self, addr: u16) {
+        self.watchpoints.remove(&addr);
+    }
+
+    /// Returns the first write to a watched address since the last call,
+    /// with the values read at the address before and after the write.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
     /// Writes |d8| into memory at |addr|.
     pub fn write_byte(&mut self, addr: u16, val: u8) {
+        #[cfg(test)]
+        {
+            self.last_write = Some((addr, val));
+        }
+
+        let is_watched = !self.watchpoints.is_empty() && self.watchpoints.contains(&addr);
+        let old = if is_watched { self.read_byte(addr) } else { 0x00 };
+
+        self._write_byte(addr, val);
+
+        if is_watched && self.watch_hit.is_none() {
+            let new = self.read_byte(addr);
+            log::info("mmu", "watchpoint", &format!("addr=0x{:04x} old=0x{:02x} new=0x{:02x}",
+                addr, old, new));
+            self.watch_hit = Some(WatchHit { addr, old, new });
+        }
+    }
+
+    // Writes |val| into the device or memory region owning |addr|.
+    fn _write_byte(&mut self, addr: u16, val: u8) {
         match addr {
-            VRAM_BEG_ADDR..=VRAM_END_ADDR => {
-                self.vram[(addr - VRAM_BEG_ADDR) as usize] = val;
+            ROM0_BEG_ADDR..=ROM0_END_ADDR |
+            ROMX_BEG_ADDR..=ROMX_END_ADDR => {
+                self.mbc.write_rom(addr, val);
+            },
+            VRAM_BEG_ADDR..=VRAM_END_ADDR |
+            OAM_BEG_ADDR..=OAM_END_ADDR => {
+                self.gpu.write_byte(addr, val);
+            },
+            ERAM_BEG_ADDR..=ERAM_END_ADDR => {
+                if let Some(offset) = self.mbc.ram_offset(addr) {
+                    let ram = self.cart.as_mut().map(|cart| cart.ram_mut());
+                    if let Some(byte) = ram.and_then(|ram| ram.get_mut(offset)) {
+                        *byte = val;
+                    }
+                }
             },
             WRAM_BEG_ADDR..=WRAM_END_ADDR => {
                 self.wram[(addr - WRAM_BEG_ADDR) as usize] = val;
@@ -142,15 +542,55 @@ impl Mmu {
             RRAM_BEG_ADDR..=RRAM_END_ADDR => {
                 self.wram[(addr - RRAM_BEG_ADDR) as usize] = val;
             }
+            IO_BEG_ADDR..=IO_END_ADDR => {
+                self._write_io(addr, val);
+            },
             ZRAM_BEG_ADDR..=ZRAM_END_ADDR => {
                 self.zram[(addr - ZRAM_BEG_ADDR) as usize] = val;
             },
+            IE_ADDR => {
+                self.int_enable = val;
+            },
             _ => {
                 /* NOP */
             },
         }
     }
 
+    // Writes |val| into the I/O register at |addr|, through the device
+    // owning it.
+    fn _write_io(&mut self, addr: u16, val: u8) {
+        match addr {
+            JOYPAD_ADDR => {
+                if self.joypad.write_byte(addr, val) {
+                    self.request_interrupt(Interrupt::Joypad);
+                }
+            },
+            TIMER_BEG_ADDR..=TIMER_END_ADDR => {
+                self.timer.write_byte(addr, val);
+            },
+            IF_ADDR => {
+                self.int_flag = val & 0x1f;
+            },
+            BIOS_UNMAP_ADDR => {
+                if val != 0x00 {
+                    self.unmap_bios();
+                }
+            },
+            DMA_ADDR => {
+                self.dma = val;
+                self.copy_block(OAM_BEG_ADDR, (val as u16) << 8, DMA_LEN);
+            },
+            LCD_REGS_BEG_ADDR..=LCD_REGS_END_ADDR => {
+                self.gpu.write_byte(addr, val);
+                self.int_flag |= self.gpu.take_interrupts();
+            },
+            _ => {
+                self.io[(addr - IO_BEG_ADDR) as usize] = val;
+            },
+        };
+    }
+
     /// Writes |d16| into memory at |addr|.
     pub fn write_word(&mut self, addr: u16, d16: u16) {
         let lsb = (d16 & 0x00ff) as u8;
@@ -158,11 +598,74 @@ impl Mmu {
         self.write_byte(addr, lsb);
         self.write_byte(addr + 1, msb);
     }
+
+    /// Copies |len| bytes from |src| to |dst|.  When both ranges lie
+    /// within RAM owned by the MMU, and no watchpoint is set, the bytes are
+    /// copied as a block; otherwise, they are copied one by one through
+    /// |read_byte| and |write_byte|.  The ranges are not expected to
+    /// overlap.
+    pub fn copy_block(&mut self, dst: u16, src: u16, len: usize) {
+        // The block copy bypasses |write_byte|, which checks the watchpoints.
+        let ranges = if self.watchpoints.is_empty() {
+            (Mmu::_get_ram_range(src, len), Mmu::_get_ram_range(dst, len))
+        } else {
+            (None, None)
+        };
+        match ranges {
+            (Some((src_ram, src_off)), Some((dst_ram, dst_off))) => {
+                let src_range = src_off..src_off + len;
+                match (src_ram, dst_ram) {
+                    (Ram::Wram, Ram::Wram) => self.wram.copy_within(src_range, dst_off),
+                    (Ram::Zram, Ram::Zram) => self.zram.copy_within(src_range, dst_off),
+                    (Ram::Wram, Ram::Zram) => self.zram[dst_off..dst_off + len]
+                        .copy_from_slice(&self.wram[src_range]),
+                    (Ram::Zram, Ram::Wram) => self.wram[dst_off..dst_off + len]
+                        .copy_from_slice(&self.zram[src_range]),
+                };
+                #[cfg(test)]
+                {
+                    if len > 0 {
+                        let last = dst.wrapping_add(len as u16 - 1);
+                        self.last_write = Some((last, self.read_byte(last)));
+                    }
+                }
+            },
+            _ => {
+                for i in 0..len as u16 {
+                    let val = self.read_byte(src.wrapping_add(i));
+                    self.write_byte(dst.wrapping_add(i), val);
+                }
+            },
+        };
+    }
+
+    // Returns the RAM array, and the offset into it, backing the range
+    // [addr, addr + len); returns |None| if the range does not lie
+    // within a single RAM array.
+    fn _get_ram_range(addr: u16, len: usize) -> Option<(Ram, usize)> {
+        let beg = addr as usize;
+        let end = beg + len;
+        let regions = [
+            (WRAM_BEG_ADDR, WRAM_END_ADDR, Ram::Wram),
+            (RRAM_BEG_ADDR, RRAM_END_ADDR, Ram::Wram),
+            (ZRAM_BEG_ADDR, ZRAM_END_ADDR, Ram::Zram),
+        ];
+
+        for (region_beg, region_end, ram) in regions.iter() {
+            if beg >= *region_beg as usize && end <= *region_end as usize + 1 {
+                return Some((*ram, beg - *region_beg as usize));
+            }
+        }
+
+        return None;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mem::cart::RAM_SIZE_ADDR;
+    use crate::mem::cart::tests::make_rom;
 
     #[test]
     fn test_read_byte() {
@@ -185,6 +688,197 @@ mod tests {
         assert_eq!(mmu.read_byte(0x00), 0x00);
     }
 
+    #[test]
+    fn test_bios_unmap() {
+        let mut mmu = Mmu::new();
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0000] = 0x42;
+        mmu.load_rom(rom);
+
+        mmu.write_byte(BIOS_UNMAP_ADDR, 0x00);
+        assert_eq!(mmu.is_bios_mapped(), true);
+        assert_eq!(mmu.read_byte(0x0000), BIOS[0]);
+
+        mmu.write_byte(BIOS_UNMAP_ADDR, 0x01);
+        assert_eq!(mmu.is_bios_mapped(), false);
+        assert_eq!(mmu.read_byte(0x0000), 0x42);
+    }
+
+    #[test]
+    fn test_rom() {
+        let mut mmu = Mmu::new();
+        let mut rom = vec![0x00; 2 * ROM_BANK_LEN];
+        rom[0x0000] = 0x99;
+        rom[0x0100] = 0x42;
+        rom[0x3fff] = 0x43;
+        rom[0x4000] = 0x24;
+        rom[0x7fff] = 0x25;
+        mmu.load_rom(rom);
+
+        // The BIOS shadows the first 256 bytes of the ROM.
+        assert_eq!(mmu.read_byte(0x0000), 0x31);
+        assert_eq!(mmu.read_byte(0x0100), 0x42);
+        assert_eq!(mmu.read_byte(0x3fff), 0x43);
+        assert_eq!(mmu.read_byte(0x4000), 0x24);
+        assert_eq!(mmu.read_byte(0x7fff), 0x25);
+
+        mmu.unmap_bios();
+        assert_eq!(mmu.read_byte(0x0000), 0x99);
+
+        // The ROM is not writable.
+        mmu.write_byte(0x0100, 0x00);
+        assert_eq!(mmu.read_byte(0x0100), 0x42);
+    }
+
+    #[test]
+    fn test_rom_mbc1() {
+        let mut mmu = Mmu::new();
+        let mut rom = vec![0x00; 64 * ROM_BANK_LEN];
+        for bank in 0..64 {
+            rom[bank * ROM_BANK_LEN] = bank as u8;
+        }
+        rom[CART_TYPE_ADDR] = 0x01;
+        mmu.load_rom(rom);
+        mmu.unmap_bios();
+
+        assert_eq!(mmu.read_byte(0x4000), 0x01);
+        mmu.write_byte(0x2000, 0x05);
+        assert_eq!(mmu.read_byte(0x4000), 0x05);
+        mmu.write_byte(0x2000, 0x00);
+        assert_eq!(mmu.read_byte(0x4000), 0x01);
+
+        // Bank 0x20 can't be selected: it maps to bank 0x21.
+        mmu.write_byte(0x4000, 0x01);
+        assert_eq!(mmu.read_byte(0x4000), 0x21);
+
+        // In mode 1, the upper bits also select the bank at 0x0000.
+        assert_eq!(mmu.read_byte(0x0000), 0x00);
+        mmu.write_byte(0x6000, 0x01);
+        assert_eq!(mmu.read_byte(0x0000), 0x20);
+    }
+
+    #[test]
+    fn test_rom_mbc5() {
+        let mut mmu = Mmu::new();
+        let mut rom = vec![0x00; 512 * ROM_BANK_LEN];
+        for bank in 0..512 {
+            rom[bank * ROM_BANK_LEN + 1] = (bank >> 8) as u8;
+            rom[bank * ROM_BANK_LEN + 2] = bank as u8;
+        }
+        rom[CART_TYPE_ADDR] = 0x19;
+        mmu.load_rom(rom);
+        mmu.unmap_bios();
+
+        mmu.write_byte(0x2000, 0x00);
+        mmu.write_byte(0x3000, 0x01);
+        assert_eq!(mmu.read_word(0x4001), 0x0001);
+        mmu.write_byte(0x2000, 0xff);
+        assert_eq!(mmu.read_word(0x4001), 0xff01);
+    }
+
+    #[test]
+    fn test_eram() {
+        let mut mmu = Mmu::new();
+        let mut rom = vec![0x00; 4 * ROM_BANK_LEN];
+        rom[CART_TYPE_ADDR] = 0x03;
+        rom[RAM_SIZE_ADDR] = 0x03;
+        mmu.load_rom(rom);
+
+        // The RAM is disabled on boot.
+        mmu.write_byte(0xa000, 0x42);
+        assert_eq!(mmu.read_byte(0xa000), 0xff);
+
+        mmu.write_byte(0x0000, 0x0a);
+        mmu.write_byte(0xa000, 0x42);
+        mmu.write_byte(0xbfff, 0x43);
+        assert_eq!(mmu.read_byte(0xa000), 0x42);
+        assert_eq!(mmu.read_byte(0xbfff), 0x43);
+
+        mmu.write_byte(0x0000, 0x00);
+        assert_eq!(mmu.read_byte(0xa000), 0xff);
+        mmu.write_byte(0xa000, 0x99);
+
+        mmu.write_byte(0x0000, 0x0a);
+        assert_eq!(mmu.read_byte(0xa000), 0x42);
+
+        // Switch to RAM bank 1.
+        mmu.write_byte(0x6000, 0x01);
+        mmu.write_byte(0x4000, 0x01);
+        assert_eq!(mmu.read_byte(0xa000), 0x00);
+        mmu.write_byte(0xa000, 0x24);
+        mmu.write_byte(0x4000, 0x00);
+        assert_eq!(mmu.read_byte(0xa000), 0x42);
+        mmu.write_byte(0x4000, 0x01);
+        assert_eq!(mmu.read_byte(0xa000), 0x24);
+    }
+
+    #[test]
+    fn test_battery_ram() {
+        let mut mmu = Mmu::new();
+        let mut rom = vec![0x00; 2 * ROM_BANK_LEN];
+        rom[CART_TYPE_ADDR] = 0x02;
+        rom[RAM_SIZE_ADDR] = 0x02;
+        mmu.load_rom(rom.clone());
+        let cart = mmu.cartridge_mut().unwrap();
+        assert_eq!(cart.battery_ram(), None);
+        assert!(cart.load_battery_ram(&[0x00; ERAM_BANK_LEN]).is_err());
+
+        rom[CART_TYPE_ADDR] = 0x03;
+        mmu.load_rom(rom);
+        mmu.write_byte(0x0000, 0x0a);
+        mmu.write_byte(0xa001, 0x42);
+        let cart = mmu.cartridge_mut().unwrap();
+        assert_eq!(cart.battery_ram().unwrap()[1], 0x42);
+
+        assert!(cart.load_battery_ram(&[0x11; 4]).is_err());
+        cart.load_battery_ram(&[0x11; ERAM_BANK_LEN]).unwrap();
+        assert_eq!(mmu.read_byte(0xa001), 0x11);
+    }
+
+    #[test]
+    fn test_cartridge() {
+        let mut mmu = Mmu::new();
+        assert!(mmu.cartridge().is_none());
+
+        mmu.load_rom(make_rom("TETRIS", 0x03, 0x00, 0x02));
+        let cart = mmu.cartridge().unwrap();
+        assert_eq!(cart.header().unwrap().title(), "TETRIS");
+        assert_eq!(cart.battery_ram().map(|ram| ram.len()), Some(ERAM_BANK_LEN));
+
+        // A ROM with a corrupted header is still inserted.
+        mmu.load_rom(vec![0x00; 2 * ROM_BANK_LEN]);
+        assert!(mmu.cartridge().unwrap().header().is_none());
+    }
+
+    #[test]
+    fn test_load_rom_unsupported_mbc() {
+        let capture = log::tests::capture();
+        let mut mmu = Mmu::new();
+        mmu.load_rom(make_rom("ROMONLY", 0x00, 0x00, 0x00));
+        mmu.load_rom(make_rom("MBC1", 0x01, 0x00, 0x00));
+        assert!(capture.lines().is_empty());
+
+        // MBC3 falls back to no banking, with a warning.
+        let mut rom = make_rom("MBC3", 0x13, 0x02, 0x00);
+        rom[2 * ROM_BANK_LEN] = 0x42;
+        mmu.load_rom(rom);
+        assert_eq!(capture.lines().len(), 1);
+        assert!(capture.lines()[0].contains("unsupported mbc Mbc3"));
+        mmu.write_byte(0x2000, 0x02);
+        assert_eq!(mmu.read_byte(0x4000), 0x00);
+    }
+
+    #[test]
+    fn test_eram_missing() {
+        let mut mmu = Mmu::new();
+        let mut rom = vec![0x00; 2 * ROM_BANK_LEN];
+        rom[CART_TYPE_ADDR] = 0x01;
+        mmu.load_rom(rom);
+        mmu.write_byte(0x0000, 0x0a);
+        mmu.write_byte(0xa000, 0x42);
+        assert_eq!(mmu.read_byte(0xa000), 0xff);
+    }
+
     #[test]
     fn test_write_read_byte() {
         let mut mmu = Mmu::new();
@@ -202,6 +896,241 @@ mod tests {
         assert_eq!(mmu.read_word(WRAM_BEG_ADDR), 0x1020);
     }
 
+    #[test]
+    fn test_open_bus_value() {
+        let mut mmu = Mmu::new();
+        mmu.unmap_bios();
+        assert_eq!(mmu.read_byte(0x0100), 0x00);
+        mmu.set_open_bus_value(0xff);
+        assert_eq!(mmu.read_byte(0x0100), 0xff);
+        assert_eq!(mmu.read_byte(0xfea0), 0xff);
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut mmu = Mmu::new();
+        assert_eq!(mmu.read_byte(IF_ADDR), 0xe0);
+        assert_eq!(mmu.pending_interrupt(), None);
+
+        mmu.request_interrupt(Interrupt::Timer);
+        mmu.request_interrupt(Interrupt::Joypad);
+        assert_eq!(mmu.read_byte(IF_ADDR), 0xf4);
+        assert_eq!(mmu.pending_interrupt(), None);
+
+        mmu.write_byte(IE_ADDR, 0x1f);
+        assert_eq!(mmu.read_byte(IE_ADDR), 0x1f);
+        assert_eq!(mmu.pending_interrupt(), Some(Interrupt::Timer));
+
+        mmu.ack_interrupt(Interrupt::Timer);
+        assert_eq!(mmu.pending_interrupt(), Some(Interrupt::Joypad));
+
+        mmu.write_byte(IF_ADDR, 0xff);
+        assert_eq!(mmu.read_byte(IF_ADDR), 0xff);
+        assert_eq!(mmu.pending_interrupt(), Some(Interrupt::VBlank));
+        assert_eq!(Interrupt::Joypad.vector(), 0x0060);
+    }
+
+    #[test]
+    fn test_io() {
+        let mut mmu = Mmu::new();
+
+        // Registers of devices which aren't emulated read back as written.
+        for addr in [0xff01, 0xff26, 0xff7f].iter() {
+            mmu.write_byte(*addr, 0x42);
+            assert_eq!(mmu.read_byte(*addr), 0x42);
+        }
+
+        // Registers of emulated devices are dispatched to them: writing DIV
+        // resets it.
+        mmu.timer.step(0x1234);
+        assert_eq!(mmu.read_byte(0xff04), 0x12);
+        mmu.write_byte(0xff04, 0x42);
+        assert_eq!(mmu.read_byte(0xff04), 0x00);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0xff06, 0x42);
+        mmu.write_byte(0xff07, 0x05);
+        assert_eq!(mmu.read_byte(0xff06), 0x42);
+        assert_eq!(mmu.read_byte(0xff07), 0xfd);
+        mmu.timer.step(16);
+        assert_eq!(mmu.read_byte(0xff05), 0x01);
+    }
+
+    #[test]
+    fn test_lcdc() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(LCDC_ADDR, 0x91);
+        assert_eq!(mmu.read_byte(LCDC_ADDR), 0x91);
+        assert_eq!(mmu.gpu.lcdc(), 0x91);
+    }
+
+    #[test]
+    fn test_ly() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(LCDC_ADDR, 0x80);
+        mmu.step(456 * 3);
+        assert_eq!(mmu.read_byte(LY_ADDR), 3);
+        mmu.write_byte(LY_ADDR, 0x42);
+        assert_eq!(mmu.read_byte(LY_ADDR), 0);
+        mmu.write_byte(LYC_ADDR, 0x42);
+        assert_eq!(mmu.read_byte(LYC_ADDR), 0x42);
+    }
+
+    #[test]
+    fn test_joypad() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(JOYPAD_ADDR, 0x20);
+        mmu.set_button(Button::Left, true);
+        assert_eq!(mmu.read_byte(JOYPAD_ADDR), 0xed);
+        assert_eq!(mmu.read_byte(IF_ADDR), 0xf0);
+
+        // Buttons of the other group don't request the interrupt.
+        mmu.write_byte(IF_ADDR, 0x00);
+        mmu.set_button(Button::Select, true);
+        assert_eq!(mmu.read_byte(JOYPAD_ADDR), 0xed);
+        assert_eq!(mmu.read_byte(IF_ADDR), 0xe0);
+    }
+
+    #[test]
+    fn test_dma() {
+        let mut mmu = Mmu::new();
+        for i in 0..DMA_LEN as u16 {
+            mmu.write_byte(0xc100 + i, i as u8 ^ 0x5a);
+        }
+        mmu.write_byte(DMA_ADDR, 0xc1);
+        assert_eq!(mmu.read_byte(DMA_ADDR), 0xc1);
+        for i in 0..DMA_LEN as u16 {
+            assert_eq!(mmu.read_byte(OAM_BEG_ADDR + i), i as u8 ^ 0x5a);
+        }
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(WRAM_BEG_ADDR, 0x10);
+        mmu.add_watchpoint(WRAM_BEG_ADDR);
+        assert_eq!(mmu.take_watch_hit(), None);
+
+        mmu.write_byte(WRAM_BEG_ADDR + 1, 0x20);
+        assert_eq!(mmu.take_watch_hit(), None);
+
+        // Only the first hit is kept until it is taken.
+        mmu.write_byte(WRAM_BEG_ADDR, 0x42);
+        mmu.write_byte(WRAM_BEG_ADDR, 0x43);
+        assert_eq!(mmu.take_watch_hit(),
+            Some(WatchHit { addr: WRAM_BEG_ADDR, old: 0x10, new: 0x42 }));
+        assert_eq!(mmu.take_watch_hit(), None);
+
+        mmu.remove_watchpoint(WRAM_BEG_ADDR);
+        mmu.write_byte(WRAM_BEG_ADDR, 0x44);
+        assert_eq!(mmu.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn test_pin_ly() {
+        let mut mmu = Mmu::new();
+        assert_eq!(mmu.read_byte(LY_ADDR), 0x00);
+        mmu.pin_ly(0x90);
+        assert_eq!(mmu.read_byte(LY_ADDR), 0x90);
+    }
+
+    #[test]
+    fn test_last_write() {
+        let mut mmu = Mmu::new();
+        assert_eq!(mmu.last_write(), None);
+        mmu.write_word(WRAM_BEG_ADDR, 0x1020);
+        assert_eq!(mmu.last_write(), Some((WRAM_BEG_ADDR + 1, 0x10)));
+    }
+
+    #[test]
+    fn test_vram() {
+        let mut mmu = Mmu::new();
+        for addr in VRAM_BEG_ADDR..=VRAM_END_ADDR {
+            mmu.write_byte(addr, 0x10);
+            assert_eq!(mmu.read_byte(addr), 0x10);
+            assert_eq!(mmu.gpu.read_byte(addr), 0x10);
+        }
+    }
+
+    #[test]
+    fn test_vram_invalidates_tile() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(VRAM_BEG_ADDR, 0xff);
+        assert_eq!(mmu.gpu.tile(0)[0], [1; 8]);
+        mmu.write_byte(VRAM_BEG_ADDR + 1, 0xff);
+        assert_eq!(mmu.gpu.tile(0)[0], [3; 8]);
+    }
+
+    #[test]
+    fn test_oam() {
+        let mut mmu = Mmu::new();
+        for addr in OAM_BEG_ADDR..=OAM_END_ADDR {
+            mmu.write_byte(addr, 0x10);
+            assert_eq!(mmu.read_byte(addr), 0x10);
+            assert_eq!(mmu.gpu.read_byte(addr), 0x10);
+        }
+    }
+
+    #[test]
+    fn test_copy_block() {
+        let mut mmu_block = Mmu::new();
+        let mut mmu_bytes = Mmu::new();
+        for i in 0..0x100 {
+            mmu_block.write_byte(WRAM_BEG_ADDR + i, i as u8);
+            mmu_bytes.write_byte(WRAM_BEG_ADDR + i, i as u8);
+        }
+
+        mmu_block.copy_block(WRAM_BEG_ADDR + 0x1000, WRAM_BEG_ADDR, 0x100);
+        for i in 0..0x100 {
+            let val = mmu_bytes.read_byte(WRAM_BEG_ADDR + i);
+            mmu_bytes.write_byte(WRAM_BEG_ADDR + 0x1000 + i, val);
+        }
+
+        for addr in WRAM_BEG_ADDR..=WRAM_END_ADDR {
+            assert_eq!(mmu_block.read_byte(addr), mmu_bytes.read_byte(addr));
+        }
+        assert_eq!(mmu_block.read_byte(WRAM_BEG_ADDR + 0x10ff), 0xff);
+    }
+
+    #[test]
+    fn test_copy_block_watchpoint() {
+        let mut mmu = Mmu::new();
+        for i in 0..0x10 {
+            mmu.write_byte(WRAM_BEG_ADDR + i, 0x80 + i as u8);
+        }
+
+        mmu.copy_block(WRAM_BEG_ADDR + 0x100, WRAM_BEG_ADDR, 0x10);
+        assert_eq!(mmu.last_write(), Some((WRAM_BEG_ADDR + 0x10f, 0x8f)));
+
+        // Writes into a watched address are reported, as with |write_byte|.
+        mmu.add_watchpoint(ZRAM_BEG_ADDR + 5);
+        mmu.copy_block(ZRAM_BEG_ADDR, WRAM_BEG_ADDR, 0x10);
+        assert_eq!(mmu.take_watch_hit(),
+            Some(WatchHit { addr: ZRAM_BEG_ADDR + 5, old: 0x00, new: 0x85 }));
+        assert_eq!(mmu.read_byte(ZRAM_BEG_ADDR + 0xf), 0x8f);
+        assert_eq!(mmu.last_write(), Some((ZRAM_BEG_ADDR + 0xf, 0x8f)));
+    }
+
+    #[test]
+    fn test_copy_block_across_regions() {
+        let mut mmu = Mmu::new();
+        for i in 0..0x10 {
+            mmu.write_byte(WRAM_BEG_ADDR + i, 0x80 + i as u8);
+        }
+
+        // WRAM to ZRAM: block copy between two RAM arrays.
+        mmu.copy_block(ZRAM_BEG_ADDR, WRAM_BEG_ADDR, 0x10);
+        // WRAM (through its echo) to VRAM: copied byte by byte.
+        mmu.copy_block(VRAM_BEG_ADDR, RRAM_BEG_ADDR, 0x10);
+        for i in 0..0x10 {
+            assert_eq!(mmu.read_byte(ZRAM_BEG_ADDR + i), 0x80 + i as u8);
+            assert_eq!(mmu.read_byte(VRAM_BEG_ADDR + i), 0x80 + i as u8);
+        }
+    }
+
     #[test]
     fn test_wram() {
         let mut mmu = Mmu::new();
@@ -1,4 +1,13 @@
 mod bios;
 
+pub mod cart;
+pub use self::cart::CartridgeHeader;
+
+pub mod joypad;
+
+pub mod mbc;
+
 mod mmu;
-pub use self::mmu::Mmu;
+pub use self::mmu::{Interrupt, Mmu, WatchHit};
+
+pub mod timer;
@@ -0,0 +1,185 @@
+use std::io;
+
+use crate::dbg::log;
+use crate::sys::state::{StateReader, StateWriter};
+
+// Timer registers:
+//
+// DIV is the upper byte of a 16-bit counter incremented every cycle, so
+// it increments at 16384 Hz; any write resets the counter.  TIMA is
+// incremented when the counter bit selected by TAC falls from 1 to 0,
+// and reloads from TMA when it overflows.
+//
+const DIV_ADDR: u16 = 0xff04;
+const TIMA_ADDR: u16 = 0xff05;
+const TMA_ADDR: u16 = 0xff06;
+const TAC_ADDR: u16 = 0xff07;
+
+// Bit of the counter feeding TIMA, indexed by TAC bits 0-1: 4096 Hz,
+// 262144 Hz, 65536 Hz and 16384 Hz.
+const TAC_COUNTER_BITS: [u16; 4] = [9, 3, 5, 7];
+
+/// Represents the timer of the GameBoy.
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+}
+
+impl Timer {
+    /// Creates an initialized Timer.
+    pub fn new() -> Timer {
+        return Timer {
+            counter: 0x0000,
+            tima: 0x00,
+            tma: 0x00,
+            tac: 0x00,
+        };
+    }
+
+    /// Reads the timer register at |addr|.
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        return match addr {
+            DIV_ADDR => (self.counter >> 8) as u8,
+            TIMA_ADDR => self.tima,
+            TMA_ADDR => self.tma,
+            TAC_ADDR => self.tac | 0xf8,
+            _ => panic!("address not owned by the timer"),
+        };
+    }
+
+    /// Writes |val| into the timer register at |addr|.
+    pub fn write_byte(&mut self, addr: u16, val: u8) {
+        match addr {
+            DIV_ADDR => {
+                self._set_counter(0x0000);
+            },
+            TIMA_ADDR => self.tima = val,
+            TMA_ADDR => self.tma = val,
+            TAC_ADDR => self.tac = val & 0x07,
+            _ => panic!("address not owned by the timer"),
+        };
+    }
+
+    /// Steps the timer for a certain number of cycles; returns |true| iff.
+    /// TIMA overflowed, i.e. the timer interrupt must be requested.
+    pub fn step(&mut self, ncycles: usize) -> bool {
+        let mut overflowed = false;
+        for _ in 0..ncycles {
+            overflowed |= self._set_counter(self.counter.wrapping_add(1));
+        }
+
+        if overflowed {
+            log::trace("timer", "step", "tima overflowed");
+        }
+
+        return overflowed;
+    }
+
+    /// Serializes the timer into |w|.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        w.write_u8(self.tima);
+        w.write_u8(self.tma);
+        w.write_u8(self.tac);
+    }
+
+    /// Restores the timer from |r|.
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.counter = r.read_u16()?;
+        self.tima = r.read_u8()?;
+        self.tma = r.read_u8()?;
+        self.tac = r.read_u8()?;
+        return Ok(());
+    }
+
+    // Returns whether the counter bit feeding TIMA is set; it is always
+    // clear while the timer is disabled.
+    fn _get_tima_input(&self, counter: u16) -> bool {
+        let is_enabled = self.tac & 0x04 != 0;
+        let bit = TAC_COUNTER_BITS[(self.tac & 0x03) as usize];
+        return is_enabled && (counter >> bit) & 0x01 != 0;
+    }
+
+    // Sets the counter, and increments TIMA on a falling edge of its input;
+    // returns |true| iff. TIMA overflowed.
+    fn _set_counter(&mut self, counter: u16) -> bool {
+        let is_falling_edge = self._get_tima_input(self.counter)
+            && !self._get_tima_input(counter);
+        self.counter = counter;
+
+        if !is_falling_edge {
+            return false;
+        }
+
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = if overflowed { self.tma } else { tima };
+        return overflowed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_div() {
+        let mut timer = Timer::new();
+        timer.step(255);
+        assert_eq!(timer.read_byte(DIV_ADDR), 0x00);
+        timer.step(1);
+        assert_eq!(timer.read_byte(DIV_ADDR), 0x01);
+        timer.step(256 * 0x41);
+        assert_eq!(timer.read_byte(DIV_ADDR), 0x42);
+
+        timer.write_byte(DIV_ADDR, 0x99);
+        assert_eq!(timer.read_byte(DIV_ADDR), 0x00);
+    }
+
+    #[test]
+    fn test_tima_disabled() {
+        let mut timer = Timer::new();
+        timer.write_byte(TAC_ADDR, 0x01);
+        timer.step(1024);
+        assert_eq!(timer.read_byte(TIMA_ADDR), 0x00);
+        assert_eq!(timer.read_byte(TAC_ADDR), 0xf9);
+    }
+
+    #[test]
+    fn test_tima_rates() {
+        for (tac, period) in [(0x04, 1024), (0x05, 16), (0x06, 64), (0x07, 256)].iter() {
+            let mut timer = Timer::new();
+            timer.write_byte(TAC_ADDR, *tac);
+            timer.step(period - 1);
+            assert_eq!(timer.read_byte(TIMA_ADDR), 0x00, "tac=0x{:02x}", tac);
+            timer.step(1);
+            assert_eq!(timer.read_byte(TIMA_ADDR), 0x01, "tac=0x{:02x}", tac);
+            timer.step(period * 9);
+            assert_eq!(timer.read_byte(TIMA_ADDR), 0x0a, "tac=0x{:02x}", tac);
+        }
+    }
+
+    #[test]
+    fn test_tima_overflow() {
+        let mut timer = Timer::new();
+        timer.write_byte(TMA_ADDR, 0xf0);
+        timer.write_byte(TIMA_ADDR, 0xfe);
+        timer.write_byte(TAC_ADDR, 0x05);
+
+        assert_eq!(timer.step(16), false);
+        assert_eq!(timer.read_byte(TIMA_ADDR), 0xff);
+        assert_eq!(timer.step(16), true);
+        assert_eq!(timer.read_byte(TIMA_ADDR), 0xf0);
+    }
+
+    #[test]
+    fn test_div_write_falling_edge() {
+        // Resetting DIV while the selected bit is set increments TIMA.
+        let mut timer = Timer::new();
+        timer.write_byte(TAC_ADDR, 0x05);
+        timer.step(8);
+        timer.write_byte(DIV_ADDR, 0x00);
+        assert_eq!(timer.read_byte(TIMA_ADDR), 0x01);
+    }
+}
@@ -1,2 +1,4 @@
+pub mod state;
+
 mod system;
-pub use self::system::System;
+pub use self::system::{System, SystemError, TraceFormat};
@@ -0,0 +1,195 @@
+use std::io;
+use std::io::{Read, Write};
+
+// Save states:
+//
+// A save state starts with a magic and a version, followed by the state of
+// each component, in a fixed order.  Values are stored in little endian,
+// and arrays are prefixed with their length.  The version must be bumped
+// whenever the layout changes; older save states are then rejected.
+//
+const MAGIC: &[u8; 4] = b"RGBS";
+const VERSION: u8 = 1;
+
+/// Serializes the state of the components into a save state, streamed
+/// to a writer.  The first write error is kept, and returned by |finish|,
+/// so that the components don't have to handle it.
+pub struct StateWriter<'a> {
+    out: &'a mut dyn Write,
+    err: Option<io::Error>,
+}
+
+impl<'a> StateWriter<'a> {
+    /// Starts a save state into |out|, with its header.
+    pub fn new(out: &'a mut dyn Write) -> StateWriter<'a> {
+        let mut w = StateWriter { out, err: None };
+        w._write(MAGIC);
+        w.write_u8(VERSION);
+        return w;
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self._write(&[val]);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.write_u8(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self._write(&val.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self._write(&val.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, val: usize) {
+        self.write_u64(val as u64);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_usize(bytes.len());
+        self._write(bytes);
+    }
+
+    /// Returns the first error which happened while writing, if any.
+    pub fn finish(self) -> io::Result<()> {
+        return match self.err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        };
+    }
+
+    // Writes |bytes|, unless a previous write failed.
+    fn _write(&mut self, bytes: &[u8]) {
+        if self.err.is_some() {
+            return;
+        }
+        if let Err(err) = self.out.write_all(bytes) {
+            self.err = Some(err);
+        }
+    }
+}
+
+/// Deserializes the state of the components from a save state, streamed
+/// from a reader.
+pub struct StateReader<'a> {
+    input: &'a mut dyn Read,
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header of the save state read from |input|; fails if it
+    /// isn't a save state, or if its version isn't supported.
+    pub fn new(input: &'a mut dyn Read) -> io::Result<StateReader<'a>> {
+        let mut r = StateReader { input };
+        let mut magic = [0; 4];
+        r._read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a save state"));
+        }
+        let version = r.read_u8()?;
+        if version != VERSION {
+            return Err(invalid_data(&format!("unsupported save state version {}", version)));
+        }
+        return Ok(r);
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        let mut bytes = [0; 1];
+        self._read_exact(&mut bytes)?;
+        return Ok(bytes[0]);
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        self._read_exact(&mut bytes)?;
+        return Ok(u16::from_le_bytes(bytes));
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        self._read_exact(&mut bytes)?;
+        return Ok(u64::from_le_bytes(bytes));
+    }
+
+    pub fn read_usize(&mut self) -> io::Result<usize> {
+        Ok(self.read_u64()? as usize)
+    }
+
+    /// Reads an array into |dst|; fails if its length doesn't match.
+    pub fn read_bytes_into(&mut self, dst: &mut [u8]) -> io::Result<()> {
+        let len = self.read_usize()?;
+        if len != dst.len() {
+            return Err(invalid_data(&format!("expected {} bytes, got {}", dst.len(), len)));
+        }
+        return self._read_exact(dst);
+    }
+
+    /// Fails if the save state has trailing data.
+    pub fn finish(self) -> io::Result<()> {
+        if self.input.read(&mut [0; 1])? != 0 {
+            return Err(invalid_data("trailing data in the save state"));
+        }
+        return Ok(());
+    }
+
+    // Fills |dst| with the next bytes.
+    fn _read_exact(&mut self, dst: &mut [u8]) -> io::Result<()> {
+        return match self.input.read_exact(dst) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(invalid_data("truncated save state"))
+            },
+            res => res,
+        };
+    }
+}
+
+/// Returns the error reported for a malformed save state.
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut data = Vec::new();
+        let mut w = StateWriter::new(&mut data);
+        w.write_u8(0x12);
+        w.write_bool(true);
+        w.write_u16(0x3456);
+        w.write_usize(0x789a);
+        w.write_bytes(&[1, 2, 3]);
+        w.finish().unwrap();
+
+        let mut input = &data[..];
+        let mut r = StateReader::new(&mut input).unwrap();
+        assert_eq!(r.read_u8().unwrap(), 0x12);
+        assert_eq!(r.read_bool().unwrap(), true);
+        assert_eq!(r.read_u16().unwrap(), 0x3456);
+        assert_eq!(r.read_usize().unwrap(), 0x789a);
+        let mut bytes = [0; 3];
+        r.read_bytes_into(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert!(r.finish().is_ok());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(StateReader::new(&mut &b"RGB"[..]).is_err());
+        assert!(StateReader::new(&mut &b"XXXX\x01"[..]).is_err());
+        assert!(StateReader::new(&mut &b"RGBS\x02"[..]).is_err());
+
+        let mut data = &b"RGBS\x01\x03\x00\x00\x00\x00\x00\x00\x00\x01"[..];
+        let mut r = StateReader::new(&mut data).unwrap();
+        assert!(r.read_bytes_into(&mut [0; 2]).is_err());
+        assert!(r.read_u16().is_err());
+    }
+}
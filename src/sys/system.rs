@@ -1,24 +1,868 @@
-use crate::cpu::{Cpu};
-use crate::mem::{Mmu};
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::cpu::{Cpu, CpuError};
+use crate::dbg::{log, BreakReason, Debugger};
+use crate::mem::joypad::ButtonState;
+use crate::mem::{CartridgeHeader, Mmu};
+use crate::sys::state::{StateReader, StateWriter};
+
+/// Frequency of the CPU clock, in cycles per second.
+pub const CPU_HZ: u64 = 4_194_304;
+
+// Values of the I/O registers when the BIOS hands over to the cartridge,
+// on a DMG.  Registers with side-effects on write (DIV resets, DMA starts
+// a transfer) or which are read-only (LY) are left out.  NR52 comes first
+// since the APU ignores writes to its registers while powered off.  The
+// mode bits of STAT are read-only and follow the GPU, which starts in OAM
+// scan (mode 2) when LCDC turns the LCD on: STAT reads back as 0x86.
+const POST_BIOS_IO_REGS: [(u16, u8); 37] = [
+    (0xff00, 0xcf), // P1
+    (0xff01, 0x00), // SB
+    (0xff02, 0x7e), // SC
+    (0xff05, 0x00), // TIMA
+    (0xff06, 0x00), // TMA
+    (0xff07, 0xf8), // TAC
+    (0xff0f, 0xe1), // IF
+    (0xff26, 0xf1), // NR52
+    (0xff10, 0x80), // NR10
+    (0xff11, 0xbf), // NR11
+    (0xff12, 0xf3), // NR12
+    (0xff13, 0xff), // NR13
+    (0xff14, 0xbf), // NR14
+    (0xff16, 0x3f), // NR21
+    (0xff17, 0x00), // NR22
+    (0xff18, 0xff), // NR23
+    (0xff19, 0xbf), // NR24
+    (0xff1a, 0x7f), // NR30
+    (0xff1b, 0xff), // NR31
+    (0xff1c, 0x9f), // NR32
+    (0xff1d, 0xff), // NR33
+    (0xff1e, 0xbf), // NR34
+    (0xff20, 0xff), // NR41
+    (0xff21, 0x00), // NR42
+    (0xff22, 0x00), // NR43
+    (0xff23, 0xbf), // NR44
+    (0xff24, 0x77), // NR50
+    (0xff25, 0xf3), // NR51
+    (0xff40, 0x91), // LCDC
+    (0xff41, 0x85), // STAT
+    (0xff42, 0x00), // SCY
+    (0xff43, 0x00), // SCX
+    (0xff45, 0x00), // LYC
+    (0xff47, 0xfc), // BGP
+    (0xff4a, 0x00), // WY
+    (0xff4b, 0x00), // WX
+    (0xffff, 0x00), // IE
+];
+
+// GameBoy Doctor does not emulate the PPU; it expects LY to always read
+// as 0x90 (i.e., the PPU sits at the start of VBlank).
+const DOCTOR_LY: u8 = 0x90;
+
+/// Enumerates the errors which can happen when stepping the system.
+#[derive(Debug)]
+pub enum SystemError {
+    /// The CPU failed to execute an instruction; |state| is the dump of
+    /// the CPU state when it did.
+    Cpu { err: CpuError, state: String },
+    /// Writing a log or a trace failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for SystemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SystemError::Cpu { err, state } => write!(f, "{}\n{}", err, state),
+            SystemError::Io(err) => write!(f, "failed to write the log: {}", err),
+        }
+    }
+}
+
+impl error::Error for SystemError {}
+
+impl From<io::Error> for SystemError {
+    fn from(err: io::Error) -> SystemError {
+        SystemError::Io(err)
+    }
+}
+
+/// Enumerates the formats of the per-step traces.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TraceFormat {
+    /// The CPU state before each step, as expected by GameBoy Doctor.
+    Doctor,
+    /// The CPU state before each step, as in the Blargg test ROM logs.
+    Blargg,
+    /// The executed opcode and the CPU state, after each step.
+    Opcode,
+}
+
+impl TraceFormat {
+    /// Parses a format from its name (e.g., "blargg"); returns |None| if
+    /// the name is unknown.
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        return match name.to_lowercase().as_str() {
+            "doctor" => Some(TraceFormat::Doctor),
+            "blargg" => Some(TraceFormat::Blargg),
+            "opcode" => Some(TraceFormat::Opcode),
+            _ => None,
+        };
+    }
+
+    // Returns |true| iff. the lines are written after the step, rather
+    // than before it.
+    fn _is_after_step(&self) -> bool {
+        *self == TraceFormat::Opcode
+    }
+}
+
+// A trace: lines in |format|, written to |writer|.
+struct TraceSink {
+    format: TraceFormat,
+    writer: Box<dyn Write>,
+}
 
 pub struct System {
     pub cpu: Cpu,
     pub mmu: Mmu,
+    pub debugger: Debugger,
+    elapsed_cycles: u64,
+    traces: Vec<TraceSink>,
 }
 
 impl System {
-
-    // TODO: add a function to load a cartridge.
-
     pub fn new() -> System {
         return System {
             cpu: Cpu::new(),
             mmu: Mmu::new(),
+            debugger: Debugger::new(),
+            elapsed_cycles: 0,
+            traces: Vec::new(),
+        };
+    }
+
+    /// Creates a system in the state the BIOS leaves it in, so execution
+    /// starts directly at the cartridge entry point (0x0100).
+    pub fn new_post_bios() -> System {
+        let mut sys = System::new();
+        sys.skip_boot();
+        return sys;
+    }
+
+    /// Skips the BIOS: unmaps it, and sets the CPU and I/O registers to
+    /// the values it leaves them with, on a DMG.
+    pub fn skip_boot(&mut self) {
+        self.cpu.set_post_bios_regs();
+        self.mmu.unmap_bios();
+        for (addr, val) in POST_BIOS_IO_REGS.iter() {
+            self.mmu.write_byte(*addr, *val);
+        }
+    }
+
+    /// Loads the cartridge ROM at |path|.  Fails if the file can't be read,
+    /// or if it doesn't contain a valid cartridge header.
+    pub fn load_rom(&mut self, path: &Path) -> io::Result<()> {
+        let rom = std::fs::read(path)?;
+        let header = CartridgeHeader::from_rom(&rom)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        log::info("sys", "load_rom", &format!("title='{}' mbc={:?} battery={}",
+            header.title(), header.mbc_kind(), header.has_battery()));
+
+        self.mmu.load_rom(rom);
+        return Ok(());
+    }
+
+    /// Restores the battery-backed RAM of the cartridge from the save file
+    /// at |path|.  Does nothing if the cartridge has no battery, or if the
+    /// file doesn't exist yet.
+    pub fn load_save(&mut self, path: &Path) -> io::Result<()> {
+        let cart = match self.mmu.cartridge_mut() {
+            Some(cart) if cart.battery_ram().is_some() => cart,
+            _ => return Ok(()),
         };
+
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        return cart.load_battery_ram(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
     }
 
-    pub fn step(&mut self) {
-        let ncycles = self.cpu.step(&mut self.mmu);
-        self.mmu.gpu.step(ncycles);
+    /// Writes the battery-backed RAM of the cartridge to the save file at
+    /// |path|.  Does nothing if the cartridge has no battery.
+    pub fn save_sram(&self, path: &Path) -> io::Result<()> {
+        return match self.mmu.cartridge().and_then(|cart| cart.battery_ram()) {
+            Some(ram) => std::fs::write(path, ram),
+            None => Ok(()),
+        };
+    }
+
+    /// Writes the CPU state to |path| before each step, in the format
+    /// expected by GameBoy Doctor.  LY is pinned to 0x90 as the tool
+    /// expects.
+    pub fn enable_doctor_log(&mut self, path: &Path) -> io::Result<()> {
+        self.enable_trace(TraceFormat::Doctor, BufWriter::new(File::create(path)?));
+        self.mmu.pin_ly(DOCTOR_LY);
+        return Ok(());
+    }
+
+    /// Writes a trace line in |format| to |writer| on each step; e.g., in
+    /// the format of the Blargg test ROM logs:
+    ///
+    ///   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 (00 C3 13 02)
+    pub fn enable_trace<W: Write + 'static>(&mut self, format: TraceFormat, writer: W) {
+        self.traces.push(TraceSink { format, writer: Box::new(writer) });
+    }
+
+    /// Writes the trace in |format| to |path|.  The trace goes to its own
+    /// buffered file, independently of the log output.
+    pub fn enable_trace_to(&mut self, path: &Path, format: TraceFormat) -> io::Result<()> {
+        self.enable_trace(format, BufWriter::new(File::create(path)?));
+        return Ok(());
+    }
+
+    /// Steps the system through one instruction.  Fails if the CPU can't
+    /// execute it, with PC left on the faulting opcode.
+    pub fn step(&mut self) -> Result<(), SystemError> {
+        self._write_traces(false)?;
+
+        let ncycles = match self.cpu.step(&mut self.mmu) {
+            Ok(ncycles) => ncycles,
+            Err(err) => return Err(SystemError::Cpu { err, state: self.dump_cpu_state() }),
+        };
+        self.mmu.step(ncycles);
+        self.elapsed_cycles += ncycles as u64;
+
+        self._write_traces(true)?;
+        return Ok(());
+    }
+
+    /// Flushes the traces; buffered lines are otherwise only written out
+    /// when the system is dropped.
+    pub fn flush_traces(&mut self) -> io::Result<()> {
+        for sink in self.traces.iter_mut() {
+            sink.writer.flush()?;
+        }
+        return Ok(());
+    }
+
+    // Writes a line to the traces written after the step if |is_after_step|,
+    // or to the ones written before it otherwise.
+    fn _write_traces(&mut self, is_after_step: bool) -> io::Result<()> {
+        for sink in self.traces.iter_mut() {
+            if sink.format._is_after_step() != is_after_step {
+                continue;
+            }
+            let line = match sink.format {
+                TraceFormat::Doctor => self.cpu.dump_state(&self.mmu),
+                TraceFormat::Blargg => self.cpu.dump_state_blargg(&self.mmu),
+                TraceFormat::Opcode => self.cpu.trace(),
+            };
+            writeln!(sink.writer, "{}", line)?;
+        }
+        return Ok(());
+    }
+
+    /// Steps the system until the debugger stops it, or a watchpoint of
+    /// the MMU is hit, and returns why.  At
+    /// least one instruction is executed, so that execution can resume
+    /// from a breakpoint.  Fails if the CPU can't execute an instruction.
+    pub fn run_until_break(&mut self) -> Result<BreakReason, SystemError> {
+        loop {
+            self.step()?;
+            let reason = match self.mmu.take_watch_hit() {
+                Some(hit) => Some(BreakReason::Watchpoint(hit)),
+                None if self.cpu.take_storm_hit() => Some(BreakReason::InterruptStorm),
+                None => self.debugger.check(self.cpu.pc()),
+            };
+            if let Some(reason) = reason {
+                log::info("sys", "run_until_break", &format!("reason={:?}", reason));
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// Adds a breakpoint at |addr|; |run_until_break| stops before the
+    /// instruction at |addr| is executed.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.debugger.add_breakpoint(addr);
+    }
+
+    /// Removes the breakpoint at |addr|, if any.
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.debugger.remove_breakpoint(addr);
+    }
+
+    /// Returns a save state of the whole system.  The cartridge ROM isn't
+    /// part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.save_state_to_writer(&mut data).expect("writing to a Vec can't fail");
+        return data;
+    }
+
+    /// Writes a save state of the whole system to |w|, as it is produced.
+    pub fn save_state_to_writer(&self, w: &mut impl Write) -> io::Result<()> {
+        let mut w = StateWriter::new(w);
+        w.write_u64(self.elapsed_cycles);
+        self.cpu.save_state(&mut w);
+        self.mmu.save_state(&mut w);
+        return w.finish();
+    }
+
+    /// Restores the system from the save state |data|; the cartridge the
+    /// state was saved with must be loaded.  Fails if |data| isn't a valid
+    /// save state; the system may then be partially restored.
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        return self.load_state_from_reader(&mut &data[..]);
+    }
+
+    /// Restores the system from the save state read from |r|, as it is
+    /// consumed; see |load_state|.
+    pub fn load_state_from_reader(&mut self, r: &mut impl Read) -> io::Result<()> {
+        let mut r = StateReader::new(r)?;
+        self.elapsed_cycles = r.read_u64()?;
+        self.cpu.load_state(&mut r)?;
+        self.mmu.load_state(&mut r)?;
+        return r.finish();
+    }
+
+    /// Returns which buttons of the joypad are pressed, e.g. to draw them
+    /// on an overlay.
+    pub fn button_state(&self) -> ButtonState {
+        self.mmu.joypad.button_state()
+    }
+
+    /// Sets the whole joypad from |bits|, e.g. for scripted input: bit 0
+    /// to 7 are Right, Left, Up, Down, A, B, Select and Start, with 1
+    /// meaning pressed.  The joypad interrupt is requested if a newly
+    /// pressed button is visible through the groups selected in P1.
+    pub fn set_joypad_from_bits(&mut self, bits: u8) {
+        self.mmu.set_buttons(bits);
+    }
+
+    /// Returns the number of CPU cycles emulated since reset.
+    pub fn elapsed_cycles(&self) -> u64 {
+        self.elapsed_cycles
+    }
+
+    /// Returns the emulated time elapsed since reset, in seconds.
+    pub fn elapsed_seconds(&self) -> f64 {
+        self.elapsed_cycles as f64 / CPU_HZ as f64
+    }
+
+    /// Returns the CPU state in the BGB / GameBoy Doctor trace format, so
+    /// that it can be diffed against reference emulator logs.
+    pub fn dump_cpu_state(&self) -> String {
+        self.cpu.dump_state(&self.mmu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::WatchHit;
+    use crate::mem::joypad::Button;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use crate::mem::cart::tests::make_rom;
+
+    #[test]
+    fn test_dump_cpu_state() {
+        let sys = System::new();
+        assert_eq!(sys.dump_cpu_state(),
+            "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0000 PCMEM:31,FE,FF,AF");
+    }
+
+    #[test]
+    fn test_load_rom() {
+        let path = std::env::temp_dir().join("rgb_test_load_rom.gb");
+        std::fs::write(&path, make_rom("TETRIS", 0x00, 0x00, 0x00)).unwrap();
+        let mut sys = System::new();
+        let res = sys.load_rom(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_save_sram() {
+        let rom_path = std::env::temp_dir().join("rgb_test_save_sram.gb");
+        let sav_path = std::env::temp_dir().join("rgb_test_save_sram.sav");
+        std::fs::write(&rom_path, make_rom("SAVE", 0x03, 0x00, 0x02)).unwrap();
+
+        let mut sys = System::new();
+        sys.load_rom(&rom_path).unwrap();
+        sys.mmu.write_byte(0x0000, 0x0a);
+        sys.mmu.write_byte(0xa000, 0x42);
+        sys.mmu.write_byte(0xbfff, 0x43);
+        sys.save_sram(&sav_path).unwrap();
+
+        let mut other = System::new();
+        other.load_rom(&rom_path).unwrap();
+        other.load_save(&sav_path).unwrap();
+        other.mmu.write_byte(0x0000, 0x0a);
+        let res = (other.mmu.read_byte(0xa000), other.mmu.read_byte(0xbfff));
+
+        std::fs::remove_file(&rom_path).unwrap();
+        std::fs::remove_file(&sav_path).unwrap();
+        assert_eq!(res, (0x42, 0x43));
+    }
+
+    #[test]
+    fn test_save_sram_no_battery() {
+        let sav_path = std::env::temp_dir().join("rgb_test_save_sram_no_battery.sav");
+        let mut sys = System::new();
+        sys.mmu.load_rom(make_rom("NOSAVE", 0x02, 0x00, 0x02));
+        sys.save_sram(&sav_path).unwrap();
+        assert!(!sav_path.exists());
+
+        // A missing save file is not an error: the game has no saves yet.
+        sys.mmu.load_rom(make_rom("SAVE", 0x03, 0x00, 0x02));
+        assert!(sys.load_save(&sav_path).is_ok());
+    }
+
+    #[test]
+    fn test_load_rom_too_small() {
+        let path = std::env::temp_dir().join("rgb_test_load_rom_too_small.gb");
+        std::fs::write(&path, [0x00; 0x100]).unwrap();
+        let mut sys = System::new();
+        let res = sys.load_rom(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_load_rom_missing() {
+        let path = std::env::temp_dir().join("rgb_test_load_rom_missing.gb");
+        let mut sys = System::new();
+        assert_eq!(sys.load_rom(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_elapsed_time() {
+        let mut sys = System::new();
+        assert_eq!(sys.elapsed_cycles(), 0);
+        assert_eq!(sys.elapsed_seconds(), 0.0);
+
+        // LD SP,d16 (12 cycles), then XOR A (4 cycles).
+        sys.step().unwrap();
+        sys.step().unwrap();
+        assert_eq!(sys.elapsed_cycles(), 16);
+        assert_eq!(sys.elapsed_seconds(), 16.0 / 4_194_304.0);
+    }
+
+    #[test]
+    fn test_button_state() {
+        let mut sys = System::new();
+        sys.mmu.set_button(Button::A, true);
+        sys.mmu.set_button(Button::Left, true);
+
+        let state = sys.button_state();
+        let pressed: Vec<Button> = Button::ALL.iter()
+            .filter(|button| state.is_pressed(**button))
+            .cloned()
+            .collect();
+        assert_eq!(pressed, vec![Button::Left, Button::A]);
+    }
+
+    #[test]
+    fn test_set_joypad_from_bits() {
+        let mut sys = System::new();
+        sys.mmu.write_byte(0xff00, 0x20);
+        sys.mmu.write_byte(0xff0f, 0x00);
+
+        // Right (bit 0) and A (bit 4): only Right is visible through the
+        // selected directions.
+        sys.set_joypad_from_bits(0x11);
+        assert!(sys.button_state().is_pressed(Button::Right));
+        assert!(sys.button_state().is_pressed(Button::A));
+        assert!(!sys.button_state().is_pressed(Button::Left));
+        assert_eq!(sys.mmu.read_byte(0xff00), 0xee);
+        assert_eq!(sys.mmu.read_byte(0xff0f) & 0x10, 0x10);
+
+        sys.mmu.write_byte(0xff00, 0x10);
+        assert_eq!(sys.mmu.read_byte(0xff00), 0xde);
+
+        // Holding the same buttons, or releasing them, doesn't interrupt.
+        sys.mmu.write_byte(0xff0f, 0x00);
+        sys.set_joypad_from_bits(0x11);
+        sys.set_joypad_from_bits(0x00);
+        assert_eq!(sys.mmu.read_byte(0xff0f) & 0x10, 0x00);
+        assert_eq!(sys.mmu.read_byte(0xff00), 0xdf);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut sys = System::new_post_bios();
+        let mut rom = make_rom("TIMER", 0x00, 0x00, 0x00);
+        rom[0x0100] = 0x18; // JR -2
+        rom[0x0101] = 0xfe;
+        sys.mmu.load_rom(rom);
+        sys.mmu.write_byte(0xff0f, 0x00);
+        sys.mmu.write_byte(0xff05, 0xff);
+        sys.mmu.write_byte(0xff07, 0x05);
+
+        // TIMA increments every 16 cycles; JR takes 12 cycles.
+        sys.step().unwrap();
+        assert_eq!(sys.mmu.read_byte(0xff0f), 0xe0);
+        sys.step().unwrap();
+        assert_eq!(sys.mmu.read_byte(0xff0f), 0xe4);
+        assert_eq!(sys.mmu.read_byte(0xff05), 0x00);
+    }
+
+    #[test]
+    fn test_cartridge_entry_point() {
+        // Cartridges start with NOP; JP 0x0150.
+        let mut sys = System::new_post_bios();
+        let mut rom = make_rom("ENTRY", 0x00, 0x00, 0x00);
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+        sys.mmu.load_rom(rom);
+
+        sys.step().unwrap();
+        sys.step().unwrap();
+        assert_eq!(sys.cpu.pc(), 0x0150);
+        assert_eq!(sys.elapsed_cycles(), 4 + 16);
+    }
+
+    #[test]
+    fn test_step_unknown_opcode() {
+        let mut sys = System::new_post_bios();
+        let mut rom = make_rom("ILLEGAL", 0x00, 0x00, 0x00);
+        rom[0x0100] = 0xd3;
+        sys.mmu.load_rom(rom);
+
+        let err = sys.step().unwrap_err();
+        match &err {
+            SystemError::Cpu { err, state } => {
+                assert_eq!(*err, CpuError::UnknownOpcode { cb: false, byte: 0xd3, pc: 0x0100 });
+                assert_eq!(*state, sys.dump_cpu_state());
+            },
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(err.to_string(), format!(
+            "unknown un-prefixed opcode 0xd3 at pc=0x0100\n{}", sys.dump_cpu_state()));
+        assert!(sys.run_until_break().is_err());
+        assert_eq!(sys.elapsed_cycles(), 0);
+    }
+
+    // Returns a system running: LD B,0x12; LD C,0x34; JR -2.
+    fn make_debug_system() -> System {
+        let mut sys = System::new_post_bios();
+        let mut rom = make_rom("DEBUG", 0x00, 0x00, 0x00);
+        rom[0x0100..0x0106].copy_from_slice(&[0x06, 0x12, 0x0e, 0x34, 0x18, 0xfe]);
+        sys.mmu.load_rom(rom);
+        return sys;
+    }
+
+    #[test]
+    fn test_run_until_break() {
+        let mut sys = make_debug_system();
+        sys.add_breakpoint(0x0104);
+        assert_eq!(sys.run_until_break().unwrap(), BreakReason::Breakpoint(0x0104));
+        assert_eq!(sys.cpu.pc(), 0x0104);
+
+        // Resuming from the breakpoint executes it again, since JR loops.
+        assert_eq!(sys.run_until_break().unwrap(), BreakReason::Breakpoint(0x0104));
+        assert_eq!(sys.elapsed_cycles(), 8 + 8 + 12);
+    }
+
+    #[test]
+    fn test_interrupt_storm() {
+        let mut sys = System::new_post_bios();
+        let mut rom = make_rom("STORM", 0x00, 0x00, 0x00);
+        // The VBlank handler requests VBlank again before returning.
+        rom[0x0040..0x0045].copy_from_slice(&[0x3e, 0x01, 0xe0, 0x0f, 0xd9]);
+        // EI; NOP; JR -2
+        rom[0x0100..0x0104].copy_from_slice(&[0xfb, 0x00, 0x18, 0xfe]);
+        sys.mmu.load_rom(rom);
+        sys.mmu.write_byte(0xffff, 0x01);
+        sys.mmu.write_byte(0xff0f, 0x01);
+        sys.cpu.set_storm_threshold(Some(16));
+
+        assert_eq!(sys.run_until_break().unwrap(), BreakReason::InterruptStorm);
+        assert_eq!(sys.cpu.pc(), 0x0040);
+
+        // Interrupts returning to code which makes progress aren't a storm.
+        let mut rom = make_rom("STORM", 0x00, 0x00, 0x00);
+        rom[0x0040] = 0xd9;
+        rom[0x0100..0x0104].copy_from_slice(&[0xfb, 0x00, 0x18, 0xfe]);
+        sys.mmu.load_rom(rom);
+        sys.cpu.set_storm_threshold(Some(2));
+        for _ in 0..4 {
+            sys.mmu.write_byte(0xff0f, 0x01);
+            for _ in 0..4 {
+                sys.step().unwrap();
+            }
+        }
+        assert!(!sys.cpu.take_storm_hit());
+    }
+
+    #[test]
+    fn test_step_mode() {
+        let mut sys = make_debug_system();
+        sys.debugger.set_stepping(true);
+        assert_eq!(sys.run_until_break().unwrap(), BreakReason::Step);
+        assert_eq!(sys.cpu.pc(), 0x0102);
+
+        sys.add_breakpoint(0x0104);
+        assert_eq!(sys.run_until_break().unwrap(), BreakReason::Breakpoint(0x0104));
+        sys.remove_breakpoint(0x0104);
+        assert_eq!(sys.run_until_break().unwrap(), BreakReason::Step);
+        assert_eq!(sys.cpu.pc(), 0x0104);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut sys = make_debug_system();
+        sys.mmu.load_rom({
+            // LD A,0x42; LD (0xC000),A; JR -2.
+            let mut rom = make_rom("WATCH", 0x00, 0x00, 0x00);
+            rom[0x0100..0x0107].copy_from_slice(&[0x3e, 0x42, 0xea, 0x00, 0xc0, 0x18, 0xfe]);
+            rom
+        });
+        sys.mmu.write_byte(0xc000, 0x10);
+        sys.mmu.add_watchpoint(0xc000);
+
+        let hit = WatchHit { addr: 0xc000, old: 0x10, new: 0x42 };
+        assert_eq!(sys.run_until_break().unwrap(), BreakReason::Watchpoint(hit));
+        assert_eq!(sys.cpu.pc(), 0x0105);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut sys = System::new_post_bios();
+        let mut rom = make_rom("STATE", 0x03, 0x00, 0x02);
+        // LD A,0x42; LD (0xC000),A; LD (0xA000),A; JR -2.
+        rom[0x0100..0x010a].copy_from_slice(
+            &[0x3e, 0x42, 0xea, 0x00, 0xc0, 0xea, 0x00, 0xa0, 0x18, 0xfe]);
+        sys.mmu.load_rom(rom.clone());
+        sys.mmu.write_byte(0x0000, 0x0a);
+        sys.mmu.write_byte(0xff05, 0x12);
+        for _ in 0..3 {
+            sys.step().unwrap();
+        }
+        let state = sys.save_state();
+        let snapshot = sys.dump_cpu_state();
+
+        for _ in 0..100 {
+            sys.step().unwrap();
+        }
+        sys.mmu.write_byte(0xc000, 0x00);
+        sys.mmu.write_byte(0xa000, 0x00);
+        sys.mmu.write_byte(0x8000, 0xff);
+        sys.mmu.write_byte(0xff05, 0x34);
+
+        sys.load_state(&state).unwrap();
+        assert_eq!(sys.save_state(), state);
+        assert_eq!(sys.dump_cpu_state(), snapshot);
+        assert_eq!(sys.elapsed_cycles(), 8 + 16 + 16);
+        assert_eq!(sys.mmu.read_byte(0xc000), 0x42);
+        assert_eq!(sys.mmu.read_byte(0xa000), 0x42);
+        assert_eq!(sys.mmu.read_byte(0x8000), 0x00);
+        assert_eq!(sys.mmu.read_byte(0xff05), 0x12);
+
+        // A fresh system, with the same cartridge, resumes from the state.
+        let mut other = System::new();
+        other.mmu.load_rom(rom);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.save_state(), state);
+    }
+
+    #[test]
+    fn test_state_streaming() {
+        let mut sys = System::new_post_bios();
+        sys.mmu.load_rom(make_rom("STREAM", 0x03, 0x00, 0x02));
+        for _ in 0..10 {
+            sys.step().unwrap();
+        }
+        let state = sys.save_state();
+
+        let mut cursor = Cursor::new(Vec::new());
+        sys.save_state_to_writer(&mut cursor).unwrap();
+        assert_eq!(cursor.get_ref(), &state);
+
+        let mut other = System::new();
+        other.mmu.load_rom(make_rom("STREAM", 0x03, 0x00, 0x02));
+        cursor.set_position(0);
+        other.load_state_from_reader(&mut cursor).unwrap();
+        assert_eq!(other.save_state(), state);
+
+        // Write errors are returned.
+        let mut buf = [0x00; 8];
+        let mut full = Cursor::new(&mut buf[..]);
+        assert_eq!(sys.save_state_to_writer(&mut full).unwrap_err().kind(),
+            io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn test_load_state_invalid() {
+        let mut sys = System::new();
+        let state = sys.save_state();
+        assert!(sys.load_state(&state[..state.len() - 1]).is_err());
+        assert!(sys.load_state(&[state.clone(), vec![0x00]].concat()).is_err());
+        assert_eq!(sys.load_state(b"nope").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_new_post_bios() {
+        let sys = System::new_post_bios();
+        assert_eq!(sys.mmu.is_bios_mapped(), false);
+        assert_eq!(sys.dump_cpu_state(),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,00,00,00");
+    }
+
+    #[test]
+    fn test_skip_boot() {
+        let mut sys = System::new();
+        sys.skip_boot();
+        assert_eq!(sys.mmu.is_bios_mapped(), false);
+        assert!(sys.dump_cpu_state().starts_with(
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100"));
+        assert_eq!(sys.mmu.read_byte(0xff40), 0x91);
+        assert_eq!(sys.mmu.read_byte(0xff47), 0xfc);
+        assert_eq!(sys.mmu.read_byte(0xff0f), 0xe1);
+    }
+
+    #[test]
+    fn test_post_bios_io_regs() {
+        let sys = System::new_post_bios();
+        for (addr, val) in POST_BIOS_IO_REGS.iter() {
+            let expected = match *addr {
+                0xff41 => (val & !0x03) | 0x02, // STAT, in mode 2.
+                _ => *val,
+            };
+            assert_eq!(sys.mmu.read_byte(*addr), expected, "register 0x{:04x}", addr);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_log_write_errors() {
+        // Writes to /dev/full fail once the buffer is flushed.
+        let full = Path::new("/dev/full");
+        let mut doctor_sys = make_debug_system();
+        doctor_sys.enable_doctor_log(full).unwrap();
+        let mut trace_sys = make_debug_system();
+        trace_sys.enable_trace_to(full, TraceFormat::Opcode).unwrap();
+
+        for sys in [&mut doctor_sys, &mut trace_sys].iter_mut() {
+            let res = (0..1000).map(|_| sys.step()).find(|res| res.is_err());
+            match res {
+                Some(Err(SystemError::Io(err))) => assert_eq!(err.raw_os_error(), Some(28)),
+                _ => panic!("expected a write error"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_doctor_log() {
+        let path = std::env::temp_dir().join("rgb_test_doctor_log.txt");
+        let mut sys = System::new();
+        sys.enable_doctor_log(&path).unwrap();
+        assert_eq!(sys.mmu.read_byte(0xff44), DOCTOR_LY);
+        for _ in 0..3 {
+            sys.step().unwrap();
+        }
+        drop(sys);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines, vec![
+            "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0000 PCMEM:31,FE,FF,AF",
+            "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0003 PCMEM:AF,21,FF,9F",
+            "A:00 F:80 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0004 PCMEM:21,FF,9F,32",
+        ]);
+    }
+
+    // A writer shared with the test, to inspect what was written.
+    #[derive(Clone)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_enable_trace() {
+        let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        let mut sys = make_debug_system();
+        sys.enable_trace(TraceFormat::Blargg, buf.clone());
+        for _ in 0..3 {
+            sys.step().unwrap();
+        }
+
+        let trace = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines, vec![
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 (06 12 0E 34)",
+            "A:01 F:B0 B:12 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 (0E 34 18 FE)",
+            "A:01 F:B0 B:12 C:34 D:00 E:D8 H:01 L:4D SP:FFFE PC:0104 (18 FE 00 00)",
+        ]);
+    }
+
+    // A writer which always fails.
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_enable_trace_write_error() {
+        let mut sys = make_debug_system();
+        sys.enable_trace(TraceFormat::Blargg, FailingWriter);
+        match sys.step() {
+            Err(SystemError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::BrokenPipe),
+            _ => panic!("expected a write error"),
+        }
+        assert_eq!(sys.elapsed_cycles(), 0);
+    }
+
+    #[test]
+    fn test_trace_format_from_name() {
+        assert_eq!(TraceFormat::from_name("blargg"), Some(TraceFormat::Blargg));
+        assert_eq!(TraceFormat::from_name("Doctor"), Some(TraceFormat::Doctor));
+        assert_eq!(TraceFormat::from_name("bgb"), None);
+    }
+
+    #[test]
+    fn test_trace_to() {
+        let path = std::env::temp_dir().join("rgb_test_trace_to.txt");
+        let mut sys = System::new();
+        sys.enable_trace_to(&path, TraceFormat::Opcode).unwrap();
+        for _ in 0..5 {
+            sys.step().unwrap();
+        }
+        sys.flush_traces().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 5);
+        drop(sys);
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("executed_opcode=opcode( is_cb:F, value=0x31"));
+        assert!(lines[1].starts_with("executed_opcode=opcode( is_cb:F, value=0xaf"));
     }
 }
@@ -0,0 +1,96 @@
+// Test fixtures:
+//
+// Builds the VRAM, tile map and OAM setups shared by the rendering tests.
+// Palettes are set to the identity (BGP=OBP0=OBP1=0xE4), so that frames
+// hold the color indices of the tiles.
+//
+use super::gpu::*;
+
+// Identity palette: color N is shade N.
+const IDENTITY_PALETTE: u8 = 0xe4;
+
+// Address of the first tile map.
+const TILE_MAP_0_ADDR: u16 = 0x9800;
+
+/// Writes the |row| of the tile |index|, from its 8 |colors|.
+pub fn write_tile_row(gpu: &mut Gpu, index: usize, row: usize, colors: &[u8; 8]) {
+    let addr = VRAM_BEG_ADDR + (index * TILE_LEN + 2 * row) as u16;
+    let (mut lsb, mut msb) = (0x00, 0x00);
+    for (x, color) in colors.iter().enumerate() {
+        lsb |= (color & 0x01) << (7 - x);
+        msb |= ((color >> 1) & 0x01) << (7 - x);
+    }
+    gpu.write_byte(addr, lsb);
+    gpu.write_byte(addr + 1, msb);
+}
+
+/// Writes the tile |index|, filled with |color|.
+pub fn write_solid_tile(gpu: &mut Gpu, index: usize, color: u8) {
+    for row in 0..8 {
+        write_tile_row(gpu, index, row, &[color; 8]);
+    }
+}
+
+/// Writes the sprite |index| of the OAM, at screen position (|x|, |y|).
+pub fn write_sprite(gpu: &mut Gpu, index: usize, x: isize, y: isize, tile: u8, attrs: u8) {
+    let addr = OAM_BEG_ADDR + (index * SPRITE_LEN) as u16;
+    gpu.write_byte(addr, (y + 16) as u8);
+    gpu.write_byte(addr + 1, (x + 8) as u8);
+    gpu.write_byte(addr + 2, tile);
+    gpu.write_byte(addr + 3, attrs);
+}
+
+/// Renders a full frame, and returns it.
+pub fn render_frame(gpu: &mut Gpu) -> Vec<u8> {
+    gpu.step(FRAME_NCYCLES);
+    return gpu.framebuffer().to_vec();
+}
+
+/// Returns a GPU rendering the background from the first tile map, and
+/// the tile data at 0x8000.  VRAM is cleared: the screen is color 0.
+pub fn make_bg_gpu() -> Gpu {
+    let mut gpu = Gpu::new();
+    gpu.write_byte(BGP_ADDR, IDENTITY_PALETTE);
+    gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE);
+    return gpu;
+}
+
+/// Returns a GPU rendering a checkerboard of 8x8 squares as background:
+/// tile 0 (color 0) and tile 1 (color 3), starting with tile 0 on the top
+/// left.
+pub fn make_checkerboard_gpu() -> Gpu {
+    let mut gpu = make_bg_gpu();
+    write_solid_tile(&mut gpu, 0, 0);
+    write_solid_tile(&mut gpu, 1, 3);
+    for y in 0..32 {
+        for x in 0..32 {
+            gpu.write_byte(TILE_MAP_0_ADDR + (y * 32 + x) as u16, ((x + y) % 2) as u8);
+        }
+    }
+    return gpu;
+}
+
+/// Returns a GPU with sprites enabled, over a blank background.
+pub fn make_sprite_gpu() -> Gpu {
+    let mut gpu = make_bg_gpu();
+    gpu.write_byte(OBP0_ADDR, IDENTITY_PALETTE);
+    gpu.write_byte(OBP1_ADDR, IDENTITY_PALETTE);
+    gpu.write_byte(LCDC_ADDR,
+        LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE | LCDC_BG_ENABLE);
+    return gpu;
+}
+
+/// Returns a GPU rendering the tile 1 (color 1) as background, and the
+/// tile 2 (color 2) as window, from the second tile map.
+pub fn make_window_gpu() -> Gpu {
+    let mut gpu = make_bg_gpu();
+    write_solid_tile(&mut gpu, 1, 1);
+    write_solid_tile(&mut gpu, 2, 2);
+    for offset in 0..0x400 {
+        gpu.write_byte(TILE_MAP_0_ADDR + offset, 1);
+        gpu.write_byte(0x9c00 + offset, 2);
+    }
+    gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE | LCDC_WIN_MAP | LCDC_WIN_ENABLE
+        | LCDC_TILE_DATA | LCDC_BG_ENABLE);
+    return gpu;
+}
@@ -1,23 +1,142 @@
+use std::io;
+
 use crate::dbg::log;
+use crate::mem::Interrupt;
+use crate::sys::state::{invalid_data, StateReader, StateWriter};
 
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
 
+// Number of cycles it takes to render a scanline, and number of scanlines
+// in a frame: 144 visible lines, then 10 lines of VBlank.
+const LINE_NCYCLES: usize = 456;
+const NLINES: u8 = 154;
+const VBLANK_LINE: u8 = 144;
+
+// Number of cycles spent in the OAM Scan and Drawing modes, from the
+// beginning of a visible scanline; HBlank takes the rest of the line.
+const OAM_SCAN_NCYCLES: usize = 80;
+const DRAWING_NCYCLES: usize = 172;
+
+/// Number of cycles it takes to render a full frame, including VBlank.
+pub const FRAME_NCYCLES: usize = LINE_NCYCLES * NLINES as usize;
+
+// Video RAM: holds the tile data and the background/window tile maps.
+pub(super) const VRAM_BEG_ADDR: u16 = 0x8000;
+const VRAM_END_ADDR: u16 = 0x9fff;
+const VRAM_LEN: usize = (VRAM_END_ADDR - VRAM_BEG_ADDR + 1) as usize;
+
+// Object Attribute Memory: holds the attributes of the 40 sprites.
+pub(super) const OAM_BEG_ADDR: u16 = 0xfe00;
+const OAM_END_ADDR: u16 = 0xfe9f;
+const OAM_LEN: usize = (OAM_END_ADDR - OAM_BEG_ADDR + 1) as usize;
+
+// LCD Control: configures what the GPU renders, and how.
+pub(super) const LCDC_ADDR: u16 = 0xff40;
+pub(super) const LCDC_LCD_ENABLE: u8 = 1 << 7;
+pub(super) const LCDC_WIN_MAP: u8 = 1 << 6;
+pub(super) const LCDC_WIN_ENABLE: u8 = 1 << 5;
+pub(super) const LCDC_TILE_DATA: u8 = 1 << 4;
+const LCDC_BG_MAP: u8 = 1 << 3;
+const LCDC_OBJ_SIZE: u8 = 1 << 2;
+pub(super) const LCDC_OBJ_ENABLE: u8 = 1 << 1;
+pub(super) const LCDC_BG_ENABLE: u8 = 1 << 0;
+
+// LCD Status: bits 0-1 report the mode, and bit 2 is set while LY == LYC;
+// they are read-only.  Bits 3-6 enable the STAT interrupt on entering
+// HBlank, VBlank, OAM Scan, and on the LY == LYC coincidence.
+const STAT_ADDR: u16 = 0xff41;
+const STAT_MODE_MASK: u8 = 0x03;
+const STAT_LYC_EQ_LY: u8 = 1 << 2;
+const STAT_HBLANK_INT: u8 = 1 << 3;
+const STAT_VBLANK_INT: u8 = 1 << 4;
+const STAT_OAM_INT: u8 = 1 << 5;
+const STAT_LYC_INT: u8 = 1 << 6;
+const STAT_WRITE_MASK: u8 = 0x78;
+
+// Scroll registers: the position of the screen in the 256x256 background.
+const SCY_ADDR: u16 = 0xff42;
+const SCX_ADDR: u16 = 0xff43;
+
+// LCD Y-coordinate: the scanline being drawn (0-153), and the value it is
+// compared with.
+const LY_ADDR: u16 = 0xff44;
+const LYC_ADDR: u16 = 0xff45;
+
+// Background palette: maps the color indices of the background and
+// window to shades, 2 bits per color.
+pub(super) const BGP_ADDR: u16 = 0xff47;
+
+// Sprite palettes: map the color indices of the sprites to shades.  The
+// color 0 is transparent, so its shade is ignored.
+pub(super) const OBP0_ADDR: u16 = 0xff48;
+pub(super) const OBP1_ADDR: u16 = 0xff49;
+
+// Window position: the window is drawn over the background from the
+// screen position (WX - 7, WY).  WX values of 166 and above hide the
+// window.
+const WY_ADDR: u16 = 0xff4a;
+const WX_ADDR: u16 = 0xff4b;
+const WX_OFFSET: usize = 7;
+const WX_HIDDEN: u8 = 166;
+
+// LCD registers which aren't emulated yet.
+const LCD_REGS_BEG_ADDR: u16 = 0xff40;
+const LCD_REGS_END_ADDR: u16 = 0xff4b;
+
+// Sprites: the OAM holds 40 entries of 4 bytes (Y, X, tile, attributes).
+// Positions are offset by 16 (Y) and 8 (X), so that sprites can be
+// partially off-screen.  At most 10 sprites are drawn per scanline.
+const NSPRITES: usize = 40;
+pub(super) const SPRITE_LEN: usize = 4;
+const MAX_SPRITES_PER_LINE: usize = 10;
+const SPRITE_BEHIND_BG: u8 = 1 << 7;
+const SPRITE_Y_FLIP: u8 = 1 << 6;
+const SPRITE_X_FLIP: u8 = 1 << 5;
+const SPRITE_PALETTE: u8 = 1 << 4;
+
+// Tile maps: two 32x32 maps of tile numbers, at 0x9800 and 0x9C00.
+const TILE_MAP_0_OFFSET: usize = 0x1800;
+const TILE_MAP_1_OFFSET: usize = 0x1c00;
+const TILE_MAP_W: usize = 32;
+
+// Tile data: 384 tiles of 8x8 pixels, 2 bits per pixel (16 bytes per
+// tile), stored at the beginning of VRAM (0x8000-0x97FF).
+const TILE_DATA_LEN: usize = 0x1800;
+pub(super) const TILE_LEN: usize = 16;
+const NTILES: usize = TILE_DATA_LEN / TILE_LEN;
+
+/// A decoded tile: one color index (0-3) per pixel, indexed by [y][x].
+pub type Tile = [[u8; 8]; 8];
+
+// Shades of the DMG, in 0RGB, indexed by color (0: lightest, 3: darkest).
+const SHADES: [u32; 4] = [0x00ffffff, 0x00aaaaaa, 0x00555555, 0x00000000];
+
+// A sprite from the OAM, positioned in screen coordinates.
+#[derive(Copy, Clone)]
+struct Sprite {
+    y: isize,
+    x: isize,
+    tile: u8,
+    attrs: u8,
+}
+
 /// Enumerates the possible mode for the GPU.
 ///
 /// ## Description of each modes
 ///
 /// Mode 2: OAM Scan
-/// TODO
+/// The GPU searches the OAM for the sprites on the scanline; 80 cycles.
 ///
 /// Mode 3: Drawing
-/// TODO
+/// The GPU draws the pixels of the scanline; 172 cycles.
 ///
 /// Mode 0: HBlank
-/// TODO
+/// The GPU idles until the end of the scanline; 204 cycles.
 ///
 /// Mode 1: VBlank
-/// TODO
+/// The GPU idles for 10 scanlines (144-153) after the last visible one;
+/// 456 cycles each.
 ///
 /// ## State diagram of the modes and their transitions:
 ///
@@ -53,51 +172,1080 @@ pub const SCREEN_H: usize = 144;
 /// Mode 0  ___000___000___000___000___000___000________________000
 /// Mode 1  ____________________________________11111111111111_____
 ///
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 enum Mode {
-    HBlank,     // Mode 0
-    VBlank,     // Mode 1
-    OamScan,    // Mode 2
-    Drawing,    // Mode 3
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
 }
 
 /// Represents the GameBoy's GPU; also known as PPU for
 /// Pixel Processing Unit.  It is responsible for rendering
 /// sprites onto the framebuffer.
+///
+/// The GPU renders into a back buffer; once a frame is complete, the back
+/// buffer is swapped with the front buffer.  The frontend only ever reads
+/// the front buffer, so it always sees a complete and consistent frame.
 pub struct Gpu {
     curr_mode: Mode,
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    win_line: usize,
+    line_ncycles: usize,
+    int_requests: u8,
+    // The STAT interrupt line: the OR of the enabled STAT sources.
+    stat_line: bool,
+    vram: [u8; VRAM_LEN],
+    oam: [u8; OAM_LEN],
+    frame_back: [u8; SCREEN_W * SCREEN_H],
+    frame_front: [u8; SCREEN_W * SCREEN_H],
+    tile_cache: [Option<Tile>; NTILES],
+    tile_ndecodes: usize,
+    // Raw background color index (0-3) of each pixel of the scanline, for
+    // sprites drawn behind the background.
+    line_bg: [u8; SCREEN_W],
 }
 
 impl Gpu {
     /// Creates a new GPU object.
     pub fn new() -> Gpu {
         return Gpu {
-            curr_mode: Mode::OamScan,
+            curr_mode: Mode::HBlank,
+            lcdc: 0x00,
+            stat: STAT_LYC_EQ_LY,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0x00,
+            obp0: 0x00,
+            obp1: 0x00,
+            wy: 0,
+            wx: 0,
+            win_line: 0,
+            line_ncycles: 0,
+            int_requests: 0x00,
+            stat_line: false,
+            vram: [0x00; VRAM_LEN],
+            oam: [0x00; OAM_LEN],
+            frame_back: [0; SCREEN_W * SCREEN_H],
+            frame_front: [0; SCREEN_W * SCREEN_H],
+            tile_cache: [None; NTILES],
+            tile_ndecodes: 0,
+            line_bg: [0; SCREEN_W],
+        };
+    }
+
+    /// Reads a byte from VRAM, OAM, or the GPU registers at |addr|.
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        return match addr {
+            LCDC_ADDR => self.lcdc,
+            STAT_ADDR => 0x80 | self.stat | (self.curr_mode as u8),
+            SCY_ADDR => self.scy,
+            SCX_ADDR => self.scx,
+            LY_ADDR => self.ly,
+            LYC_ADDR => self.lyc,
+            BGP_ADDR => self.bgp,
+            OBP0_ADDR => self.obp0,
+            OBP1_ADDR => self.obp1,
+            WY_ADDR => self.wy,
+            WX_ADDR => self.wx,
+            LCD_REGS_BEG_ADDR..=LCD_REGS_END_ADDR => 0xff,
+            VRAM_BEG_ADDR..=VRAM_END_ADDR => {
+                self.vram[(addr - VRAM_BEG_ADDR) as usize]
+            },
+            OAM_BEG_ADDR..=OAM_END_ADDR => {
+                self.oam[(addr - OAM_BEG_ADDR) as usize]
+            },
+            _ => panic!("address not owned by the gpu"),
+        };
+    }
+
+    /// Writes |val| into VRAM, OAM, or the GPU registers at |addr|.
+    pub fn write_byte(&mut self, addr: u16, val: u8) {
+        match addr {
+            LCDC_ADDR => self._set_lcdc(val),
+            STAT_ADDR => {
+                self.stat = (self.stat & !STAT_WRITE_MASK) | (val & STAT_WRITE_MASK);
+                self._update_stat_line();
+            },
+            SCY_ADDR => self.scy = val,
+            SCX_ADDR => self.scx = val,
+            LY_ADDR => {
+                // LY is read-only; writing it resets the scanline.
+                self.line_ncycles = 0;
+                self._set_ly(0);
+            },
+            LYC_ADDR => {
+                self.lyc = val;
+                self._update_coincidence();
+            },
+            BGP_ADDR => self.bgp = val,
+            OBP0_ADDR => self.obp0 = val,
+            OBP1_ADDR => self.obp1 = val,
+            WY_ADDR => self.wy = val,
+            WX_ADDR => self.wx = val,
+            LCD_REGS_BEG_ADDR..=LCD_REGS_END_ADDR => { /* NOP */ },
+            VRAM_BEG_ADDR..=VRAM_END_ADDR => {
+                let offset = (addr - VRAM_BEG_ADDR) as usize;
+                self.vram[offset] = val;
+                self._invalidate_tile(offset);
+            },
+            OAM_BEG_ADDR..=OAM_END_ADDR => {
+                self.oam[(addr - OAM_BEG_ADDR) as usize] = val;
+            },
+            _ => panic!("address not owned by the gpu"),
         };
     }
 
     /// Steps the GPU for a certain number of cycles.
     pub fn step(&mut self, ncycles: usize) {
-        // TODO.
+        if !self.is_lcd_enabled() {
+            return;
+        }
 
-        log::info("gpu", "step", &format!("ncycles={}", ncycles));
+        self.line_ncycles += ncycles;
+        loop {
+            match self.curr_mode {
+                Mode::OamScan if self.line_ncycles >= OAM_SCAN_NCYCLES => {
+                    self._set_mode(Mode::Drawing);
+                },
+                Mode::Drawing if self.line_ncycles >= OAM_SCAN_NCYCLES + DRAWING_NCYCLES => {
+                    self._render_scanline();
+                    self._set_mode(Mode::HBlank);
+                },
+                Mode::HBlank if self.line_ncycles >= LINE_NCYCLES => {
+                    self.line_ncycles -= LINE_NCYCLES;
+                    self._set_ly(self.ly + 1);
+                    if self.ly == VBLANK_LINE {
+                        self.win_line = 0;
+                        self._swap_buffers();
+                        self._request_interrupt(Interrupt::VBlank);
+                        self._set_mode(Mode::VBlank);
+                    } else {
+                        self._set_mode(Mode::OamScan);
+                    }
+                },
+                Mode::VBlank if self.line_ncycles >= LINE_NCYCLES => {
+                    self.line_ncycles -= LINE_NCYCLES;
+                    self._set_ly((self.ly + 1) % NLINES);
+                    if self.ly == 0 {
+                        self._set_mode(Mode::OamScan);
+                    }
+                },
+                _ => break,
+            };
+        }
+
+        if log::is_enabled(log::Level::Trace, "gpu") {
+            log::trace("gpu", "step", &format!("ncycles={} ly={} mode={:?}",
+                ncycles, self.ly, self.curr_mode));
+        }
+    }
+
+    // Sets the LCD Control register.  Turning the LCD off resets the GPU
+    // to the beginning of the frame; turning it on starts an OAM Scan.
+    fn _set_lcdc(&mut self, val: u8) {
+        let was_enabled = self.is_lcd_enabled();
+        self.lcdc = val;
+
+        if was_enabled && !self.is_lcd_enabled() {
+            self.line_ncycles = 0;
+            self.curr_mode = Mode::HBlank;
+            self.win_line = 0;
+            self._set_ly(0);
+        } else if !was_enabled && self.is_lcd_enabled() {
+            self.curr_mode = Mode::OamScan;
+        }
+    }
+
+    /// Returns the interrupts requested by the GPU since the last call, as
+    /// a mask of IF bits.
+    pub fn take_interrupts(&mut self) -> u8 {
+        let res = self.int_requests;
+        self.int_requests = 0x00;
+        return res;
+    }
+
+    // Requests the |int| interrupt.
+    fn _request_interrupt(&mut self, int: Interrupt) {
+        self.int_requests |= int.bit();
+    }
+
+    // Enters |mode|, and requests the STAT interrupt if enabled for it.
+    fn _set_mode(&mut self, mode: Mode) {
+        self.curr_mode = mode;
+        self._update_stat_line();
+    }
+
+    // Moves to scanline |ly|.
+    fn _set_ly(&mut self, ly: u8) {
+        self.ly = ly;
+        self._update_coincidence();
     }
 
+    // Updates the LY == LYC flag of STAT, and requests the STAT interrupt
+    // when the coincidence starts, if enabled.
+    fn _update_coincidence(&mut self) {
+        if self.ly == self.lyc {
+            self.stat |= STAT_LYC_EQ_LY;
+        } else {
+            self.stat &= !STAT_LYC_EQ_LY;
+        }
+        self._update_stat_line();
+    }
+
+    // Returns the level of the STAT interrupt line: high iff. one of the
+    // enabled sources (the current mode, or LY == LYC) is active.
+    fn _get_stat_line(&self) -> bool {
+        let mode_int = match self.curr_mode {
+            Mode::HBlank => STAT_HBLANK_INT,
+            Mode::VBlank => STAT_VBLANK_INT,
+            Mode::OamScan => STAT_OAM_INT,
+            Mode::Drawing => 0x00,
+        };
+        let is_lyc_active = self.stat & STAT_LYC_INT != 0 && self.stat & STAT_LYC_EQ_LY != 0;
+        return self.stat & mode_int != 0 || is_lyc_active;
+    }
+
+    // Updates the STAT interrupt line.  The interrupt is only requested on
+    // its rising edge: a source becoming active while another one already
+    // holds the line high doesn't request it again (i.e., STAT blocking).
+    fn _update_stat_line(&mut self) {
+        let stat_line = self._get_stat_line();
+        if stat_line && !self.stat_line {
+            self._request_interrupt(Interrupt::LcdStat);
+        }
+        self.stat_line = stat_line;
+    }
+
+    /// Returns the LCD Control register.
+    ///
+    /// Bit 7: LCD enable
+    /// Bit 6: window tile map (0: 0x9800, 1: 0x9C00)
+    /// Bit 5: window enable
+    /// Bit 4: BG & window tile data (0: 0x8800, 1: 0x8000)
+    /// Bit 3: BG tile map (0: 0x9800, 1: 0x9C00)
+    /// Bit 2: OBJ size (0: 8x8, 1: 8x16)
+    /// Bit 1: OBJ enable
+    /// Bit 0: BG & window enable
+    pub fn lcdc(&self) -> u8 {
+        self.lcdc
+    }
+
+    /// Returns |true| iff. the LCD is enabled.
+    pub fn is_lcd_enabled(&self) -> bool {
+        self.lcdc & LCDC_LCD_ENABLE != 0
+    }
+
+    /// Returns the last complete frame, as one color index (0-3) per pixel.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.frame_front
+    }
+
+    /// Returns the decoded tile |index| from the tile data.  Tiles are
+    /// decoded once, then cached until their tile data is written.
+    pub fn tile(&mut self, index: usize) -> &Tile {
+        if self.tile_cache[index].is_none() {
+            self.tile_cache[index] = Some(Gpu::_decode_tile(
+                &self.vram[index * TILE_LEN..(index + 1) * TILE_LEN]));
+            self.tile_ndecodes += 1;
+        }
+
+        return self.tile_cache[index].as_ref().unwrap();
+    }
+
+    // Renders the current scanline into the back buffer.
+    fn _render_scanline(&mut self) {
+        let y = self.ly as usize;
+        let bg_map = if self.lcdc & LCDC_BG_MAP != 0 { TILE_MAP_1_OFFSET } else { TILE_MAP_0_OFFSET };
+        let win_map = if self.lcdc & LCDC_WIN_MAP != 0 { TILE_MAP_1_OFFSET } else { TILE_MAP_0_OFFSET };
+
+        // The window is only drawn when both the background and the window
+        // are enabled, and it is within the screen.
+        let is_win_visible = self.lcdc & LCDC_BG_ENABLE != 0
+            && self.lcdc & LCDC_WIN_ENABLE != 0
+            && y >= self.wy as usize
+            && self.wx < WX_HIDDEN;
+
+        for x in 0..SCREEN_W {
+            let color = if is_win_visible && x + WX_OFFSET >= self.wx as usize {
+                let win_x = x + WX_OFFSET - self.wx as usize;
+                self._get_map_color(win_map, win_x, self.win_line)
+            } else if self.lcdc & LCDC_BG_ENABLE != 0 {
+                // The background wraps around.
+                let bg_x = (x + self.scx as usize) % 256;
+                let bg_y = (y + self.scy as usize) % 256;
+                self._get_map_color(bg_map, bg_x, bg_y)
+            } else {
+                0
+            };
+            self.line_bg[x] = color;
+            self.frame_back[y * SCREEN_W + x] = Gpu::_apply_palette(self.bgp, color);
+        }
+
+        // The window has its own line counter, which only advances on the
+        // scanlines where the window is drawn.
+        if is_win_visible {
+            self.win_line += 1;
+        }
+
+        if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            self._render_sprites(y);
+        }
+    }
+
+    // Returns the sprite |index| of the OAM.
+    fn _get_sprite(&self, index: usize) -> Sprite {
+        let entry = &self.oam[index * SPRITE_LEN..(index + 1) * SPRITE_LEN];
+        return Sprite {
+            y: entry[0] as isize - 16,
+            x: entry[1] as isize - 8,
+            tile: entry[2],
+            attrs: entry[3],
+        };
+    }
+
+    // Renders the sprites of scanline |y| over the background.
+    fn _render_sprites(&mut self, y: usize) {
+        let height = if self.lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 };
+        let y = y as isize;
+
+        // Only the first 10 sprites of the OAM on the scanline are drawn.
+        let mut sprites: Vec<Sprite> = (0..NSPRITES)
+            .map(|index| self._get_sprite(index))
+            .filter(|sprite| y >= sprite.y && y < sprite.y + height)
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+
+        // The sprite with the smallest X has priority; ties are broken by
+        // the OAM order.  Each pixel belongs to the opaque pixel of highest
+        // priority, even if it ends up hidden behind the background.
+        sprites.sort_by_key(|sprite| sprite.x);
+        let mut is_drawn = [false; SCREEN_W];
+
+        for sprite in sprites.iter() {
+            let mut row = (y - sprite.y) as usize;
+            if sprite.attrs & SPRITE_Y_FLIP != 0 {
+                row = height as usize - 1 - row;
+            }
+
+            // In 8x16 mode, the sprite is made of two consecutive tiles;
+            // the lower bit of the tile number is ignored.
+            let tile_num = if height == 16 {
+                (sprite.tile & 0xfe) as usize + row / 8
+            } else {
+                sprite.tile as usize
+            };
+            let tile = *self.tile(tile_num);
+            let palette = if sprite.attrs & SPRITE_PALETTE != 0 { self.obp1 } else { self.obp0 };
+
+            for col in 0..8 {
+                let x = sprite.x + col as isize;
+                if x < 0 || x >= SCREEN_W as isize || is_drawn[x as usize] {
+                    continue;
+                }
+                let x = x as usize;
+
+                let tile_col = if sprite.attrs & SPRITE_X_FLIP != 0 { 7 - col } else { col };
+                let color = tile[row % 8][tile_col];
+                if color == 0 {
+                    continue;
+                }
+                is_drawn[x] = true;
+
+                if sprite.attrs & SPRITE_BEHIND_BG != 0 && self.line_bg[x] != 0 {
+                    continue;
+                }
+                self.frame_back[y as usize * SCREEN_W + x] = Gpu::_apply_palette(palette, color);
+            }
+        }
+    }
+
+    // Returns the color index at (|x|, |y|) of the 256x256 background
+    // described by the tile map at |map_offset| in VRAM.
+    fn _get_map_color(&mut self, map_offset: usize, x: usize, y: usize) -> u8 {
+        let tile_num = self.vram[map_offset + (y / 8) * TILE_MAP_W + (x / 8)];
+        let index = self._get_tile_index(tile_num);
+        return self.tile(index)[y % 8][x % 8];
+    }
+
+    // Returns the index in the tile data of the background or window tile
+    // numbered |tile_num|.  With LCDC bit 4 set, tiles are numbered from
+    // 0x8000 (0-255); otherwise, from 0x9000 (-128-127).
+    fn _get_tile_index(&self, tile_num: u8) -> usize {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            return tile_num as usize;
+        }
+        return (256 + (tile_num as i8) as isize) as usize;
+    }
+
+    // Maps the color index |color| to a shade through |palette|.
+    fn _apply_palette(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 0x03
+    }
+
+    // Invalidates the cached decode of the tile containing the tile data
+    // byte at |offset| (relative to the start of VRAM).
+    fn _invalidate_tile(&mut self, offset: usize) {
+        if offset < TILE_DATA_LEN {
+            self.tile_cache[offset / TILE_LEN] = None;
+        }
+    }
+
+    // Decodes a tile.  Each row is made of two bytes: the first holds the
+    // low bit of each pixel's color, the second holds the high bit.  The
+    // leftmost pixel is the most significant bit.
+    fn _decode_tile(data: &[u8]) -> Tile {
+        let mut tile = [[0; 8]; 8];
+        for (row, bytes) in tile.iter_mut().zip(data.chunks(2)) {
+            let (lsb, msb) = (bytes[0], bytes[1]);
+            for (x, color) in row.iter_mut().enumerate() {
+                let bit = 7 - x;
+                *color = (((msb >> bit) & 0x01) << 1) | ((lsb >> bit) & 0x01);
+            }
+        }
+        return tile;
+    }
+
+    /// Serializes the GPU into |w|: registers, memory, and framebuffers.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.curr_mode as u8);
+        for reg in [self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc,
+                self.bgp, self.obp0, self.obp1, self.wy, self.wx, self.int_requests].iter() {
+            w.write_u8(*reg);
+        }
+        w.write_usize(self.win_line);
+        w.write_usize(self.line_ncycles);
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.oam);
+        w.write_bytes(&self.frame_back);
+        w.write_bytes(&self.frame_front);
+    }
+
+    /// Restores the GPU from |r|.
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.curr_mode = match r.read_u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            3 => Mode::Drawing,
+            mode => return Err(invalid_data(&format!("invalid gpu mode {}", mode))),
+        };
+        for reg in [&mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx,
+                &mut self.ly, &mut self.lyc, &mut self.bgp, &mut self.obp0,
+                &mut self.obp1, &mut self.wy, &mut self.wx, &mut self.int_requests].iter_mut() {
+            **reg = r.read_u8()?;
+        }
+        self.win_line = r.read_usize()?;
+        self.line_ncycles = r.read_usize()?;
+        r.read_bytes_into(&mut self.vram)?;
+        r.read_bytes_into(&mut self.oam)?;
+        r.read_bytes_into(&mut self.frame_back)?;
+        r.read_bytes_into(&mut self.frame_front)?;
+        self.stat_line = self._get_stat_line();
+        self.tile_cache = [None; NTILES];
+        return Ok(());
+    }
+
+    // Presents the frame rendered in the back buffer.
+    fn _swap_buffers(&mut self) {
+        std::mem::swap(&mut self.frame_back, &mut self.frame_front);
+    }
 
     /// Renders the framebuffer into a screen region, according to the selected
     /// palette.  Note the |dst| buffer is a 0RGB.  The upper 8-bits are ignored,
     /// the next 8-bits are for the red channel, the next 8-bits afterwards for
     /// the green channel, and the lower 8-bits for the blue channel.
+    ///
+    /// The screen is blank (i.e., the lightest shade) while the LCD is
+    /// disabled.
     pub fn copy_screen(&self, dst: &mut [u32]) {
         assert_eq!(dst.len(), SCREEN_W * SCREEN_H);
 
-        // TODO: renders something real.
+        if !self.is_lcd_enabled() {
+            for pixel in dst.iter_mut() {
+                *pixel = SHADES[0];
+            }
+            return;
+        }
+
+        for (ind, color) in self.frame_front.iter().enumerate() {
+            dst[ind] = SHADES[*color as usize];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vid::fixtures::*;
+
+    #[test]
+    fn test_tile_cache() {
+        let mut gpu = Gpu::new();
+        let tile_addr = VRAM_BEG_ADDR + TILE_LEN as u16;
+
+        // Tile 1, first row: colors 0, 1, 2, 3, 0, 0, 0, 0.
+        gpu.write_byte(tile_addr, 0b0101_0000);
+        gpu.write_byte(tile_addr + 1, 0b0011_0000);
+        assert_eq!(gpu.tile(1)[0], [0, 1, 2, 3, 0, 0, 0, 0]);
+        assert_eq!(gpu.tile_ndecodes, 1);
+
+        // Cached: reading the tile again does not decode it.
+        assert_eq!(gpu.tile(1)[0], [0, 1, 2, 3, 0, 0, 0, 0]);
+        assert_eq!(gpu.tile_ndecodes, 1);
+
+        // Writing another tile leaves the cached decode alone.
+        gpu.write_byte(VRAM_BEG_ADDR, 0xff);
+        assert_eq!(gpu.tile(1)[0], [0, 1, 2, 3, 0, 0, 0, 0]);
+        assert_eq!(gpu.tile_ndecodes, 1);
+
+        // Overwriting a byte of the tile invalidates it.
+        gpu.write_byte(tile_addr, 0b1111_1111);
+        assert_eq!(gpu.tile(1)[0], [1, 1, 3, 3, 1, 1, 1, 1]);
+        assert_eq!(gpu.tile_ndecodes, 2);
+    }
+
+    #[test]
+    fn test_tile_cache_across_frames() {
+        let mut gpu = make_checkerboard_gpu();
+        render_frame(&mut gpu);
+        assert_eq!(gpu.tile_ndecodes, 2);
+
+        // Nothing changed in VRAM: the second frame decodes no tile.
+        let frame = render_frame(&mut gpu);
+        assert_eq!(gpu.tile_ndecodes, 2);
+        assert_eq!([frame[0], frame[8]], [0, 3]);
+    }
+
+    #[test]
+    fn test_lcdc() {
+        let mut gpu = Gpu::new();
+        assert_eq!(gpu.is_lcd_enabled(), false);
+
+        gpu.write_byte(LCDC_ADDR, 0x91);
+        assert_eq!(gpu.read_byte(LCDC_ADDR), 0x91);
+        assert_eq!(gpu.lcdc(), 0x91);
+        assert_eq!(gpu.is_lcd_enabled(), true);
+    }
+
+    #[test]
+    fn test_ly() {
+        let mut gpu = Gpu::new();
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE);
+        assert_eq!(gpu.read_byte(LY_ADDR), 0);
+
+        gpu.step(LINE_NCYCLES - 4);
+        assert_eq!(gpu.read_byte(LY_ADDR), 0);
+        gpu.step(4);
+        assert_eq!(gpu.read_byte(LY_ADDR), 1);
+
+        // LY wraps around after 154 scanlines.
+        for ly in 2..NLINES {
+            gpu.step(LINE_NCYCLES);
+            assert_eq!(gpu.read_byte(LY_ADDR), ly);
+        }
+        gpu.step(LINE_NCYCLES);
+        assert_eq!(gpu.read_byte(LY_ADDR), 0);
+
+        // Writing LY resets it.
+        gpu.step(LINE_NCYCLES * 10);
+        assert_eq!(gpu.read_byte(LY_ADDR), 10);
+        gpu.write_byte(LY_ADDR, 0x42);
+        assert_eq!(gpu.read_byte(LY_ADDR), 0);
+    }
+
+    #[test]
+    fn test_ly_lcd_disabled() {
+        let mut gpu = Gpu::new();
+        gpu.step(LINE_NCYCLES * 2);
+        assert_eq!(gpu.read_byte(LY_ADDR), 0);
+    }
+
+    #[test]
+    fn test_lyc() {
+        let mut gpu = Gpu::new();
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE);
+        gpu.write_byte(LYC_ADDR, 2);
+        assert_eq!(gpu.read_byte(LYC_ADDR), 2);
+        assert_eq!(gpu.stat & STAT_LYC_EQ_LY, 0);
+
+        // Without the STAT interrupt enabled, only the flag is set.
+        gpu.step(LINE_NCYCLES * 2);
+        assert_eq!(gpu.stat & STAT_LYC_EQ_LY, STAT_LYC_EQ_LY);
+        assert_eq!(gpu.take_interrupts(), 0x00);
+        gpu.step(LINE_NCYCLES);
+        assert_eq!(gpu.stat & STAT_LYC_EQ_LY, 0);
+
+        gpu.write_byte(STAT_ADDR, STAT_LYC_INT);
+        gpu.write_byte(LYC_ADDR, 3);
+        assert_eq!(gpu.take_interrupts(), Interrupt::LcdStat.bit());
+        assert_eq!(gpu.take_interrupts(), 0x00);
+    }
+
+    #[test]
+    fn test_stat() {
+        let mut gpu = Gpu::new();
+        gpu.write_byte(LYC_ADDR, 1);
+        assert_eq!(gpu.read_byte(STAT_ADDR), 0x80);
+
+        for mode in [Mode::HBlank, Mode::VBlank, Mode::OamScan, Mode::Drawing].iter() {
+            gpu._set_mode(*mode);
+            assert_eq!(gpu.read_byte(STAT_ADDR) & STAT_MODE_MASK, *mode as u8);
+        }
+
+        // The lower 3 bits are read-only.
+        gpu.write_byte(LYC_ADDR, 0);
+        gpu._set_mode(Mode::HBlank);
+        gpu.write_byte(STAT_ADDR, 0xff);
+        assert_eq!(gpu.read_byte(STAT_ADDR), 0xfc);
+        gpu.write_byte(STAT_ADDR, 0x00);
+        assert_eq!(gpu.read_byte(STAT_ADDR), 0x84);
+    }
+
+    #[test]
+    fn test_stat_mode_interrupts() {
+        let mut gpu = Gpu::new();
+
+        gpu.write_byte(STAT_ADDR, STAT_HBLANK_INT | STAT_OAM_INT);
+        gpu._set_mode(Mode::OamScan);
+        assert_eq!(gpu.take_interrupts(), Interrupt::LcdStat.bit());
+        gpu._set_mode(Mode::Drawing);
+        assert_eq!(gpu.take_interrupts(), 0x00);
+        gpu._set_mode(Mode::HBlank);
+        assert_eq!(gpu.take_interrupts(), Interrupt::LcdStat.bit());
+        gpu._set_mode(Mode::VBlank);
+        assert_eq!(gpu.take_interrupts(), 0x00);
+
+        gpu.write_byte(STAT_ADDR, STAT_VBLANK_INT);
+        gpu._set_mode(Mode::VBlank);
+        assert_eq!(gpu.take_interrupts(), Interrupt::LcdStat.bit());
+    }
+
+    #[test]
+    fn test_stat_blocking() {
+        let mut gpu = Gpu::new();
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE);
+        gpu.write_byte(LYC_ADDR, 1);
+        gpu.write_byte(STAT_ADDR, STAT_LYC_INT | STAT_OAM_INT | STAT_HBLANK_INT);
+        assert_eq!(gpu.take_interrupts(), Interrupt::LcdStat.bit());
+
+        // Line 0, HBlank: the mode 0 source raises the line.
+        gpu.step(OAM_SCAN_NCYCLES + DRAWING_NCYCLES);
+        assert_eq!(gpu.take_interrupts(), Interrupt::LcdStat.bit());
+
+        // Line 1: LY == LYC, then OAM Scan.  The line stays high across
+        // the mode 0 -> mode 2 change, so neither source requests it.
+        gpu.step(LINE_NCYCLES - OAM_SCAN_NCYCLES - DRAWING_NCYCLES);
+        assert_eq!(gpu.read_byte(LY_ADDR), 1);
+        assert_eq!(gpu.read_byte(STAT_ADDR) & STAT_MODE_MASK, Mode::OamScan as u8);
+        assert_eq!(gpu.take_interrupts(), 0x00);
+
+        // Drawing keeps the line high, since LY == LYC; HBlank as well.
+        gpu.step(OAM_SCAN_NCYCLES + DRAWING_NCYCLES);
+        assert_eq!(gpu.take_interrupts(), 0x00);
+
+        // Line 2: the coincidence ends, but HBlank -> OAM Scan keeps the
+        // line high.  It only falls during Drawing, and rises on HBlank.
+        gpu.step(LINE_NCYCLES - OAM_SCAN_NCYCLES - DRAWING_NCYCLES);
+        assert_eq!(gpu.take_interrupts(), 0x00);
+        gpu.step(OAM_SCAN_NCYCLES + DRAWING_NCYCLES);
+        assert_eq!(gpu.take_interrupts(), Interrupt::LcdStat.bit());
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut gpu = Gpu::new();
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE);
+
+        // Visible scanlines: OAM Scan, Drawing, then HBlank.
+        for ly in 0..VBLANK_LINE {
+            assert_eq!((gpu.ly, gpu.curr_mode), (ly, Mode::OamScan));
+            gpu.step(80);
+            assert_eq!((gpu.ly, gpu.curr_mode), (ly, Mode::Drawing));
+            gpu.step(172);
+            assert_eq!((gpu.ly, gpu.curr_mode), (ly, Mode::HBlank));
+            gpu.step(200);
+            assert_eq!((gpu.ly, gpu.curr_mode), (ly, Mode::HBlank));
+            assert_eq!(gpu.take_interrupts(), 0x00);
+            gpu.step(4);
+        }
+
+        // VBlank scanlines.
+        assert_eq!(gpu.take_interrupts(), Interrupt::VBlank.bit());
+        for ly in VBLANK_LINE..NLINES {
+            assert_eq!((gpu.ly, gpu.curr_mode), (ly, Mode::VBlank));
+            gpu.step(LINE_NCYCLES);
+        }
+
+        // Back to the first scanline.
+        assert_eq!((gpu.ly, gpu.curr_mode), (0, Mode::OamScan));
+        assert_eq!(gpu.take_interrupts(), 0x00);
+    }
+
+    #[test]
+    fn test_frame() {
+        let mut gpu = Gpu::new();
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE);
+
+        // Large steps go through every transition.
+        gpu.step(FRAME_NCYCLES - 4);
+        assert_eq!((gpu.ly, gpu.curr_mode), (153, Mode::VBlank));
+        assert_eq!(gpu.take_interrupts(), Interrupt::VBlank.bit());
+        gpu.step(4 + OAM_SCAN_NCYCLES);
+        assert_eq!((gpu.ly, gpu.curr_mode), (0, Mode::Drawing));
+    }
+
+    #[test]
+    fn test_lcd_disable_resets() {
+        let mut gpu = Gpu::new();
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE);
+        gpu.step(LINE_NCYCLES * 3 + 100);
+        assert_eq!((gpu.ly, gpu.curr_mode), (3, Mode::Drawing));
+
+        gpu.write_byte(LCDC_ADDR, 0x00);
+        assert_eq!((gpu.ly, gpu.curr_mode), (0, Mode::HBlank));
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE);
+        assert_eq!((gpu.ly, gpu.curr_mode), (0, Mode::OamScan));
+        gpu.step(OAM_SCAN_NCYCLES);
+        assert_eq!(gpu.curr_mode, Mode::Drawing);
+    }
+
+    #[test]
+    fn test_bg() {
+        let mut gpu = make_bg_gpu();
+        write_tile_row(&mut gpu, 1, 0, &[0, 1, 2, 3, 0, 0, 0, 0]);
+        write_solid_tile(&mut gpu, 2, 3);
+
+        // Map tile 1 at (1, 0) and tile 2 at (0, 1).
+        gpu.write_byte(0x9801, 1);
+        gpu.write_byte(0x9820, 2);
+
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[0..16], [0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 0, 0, 0, 0]);
+        assert_eq!(frame[SCREEN_W..SCREEN_W + 8], [0; 8]);
+        assert_eq!(frame[8 * SCREEN_W..8 * SCREEN_W + 9], [3, 3, 3, 3, 3, 3, 3, 3, 0]);
+    }
+
+    #[test]
+    fn test_bg_checkerboard() {
+        let mut gpu = make_checkerboard_gpu();
+        let frame = render_frame(&mut gpu);
         for y in 0..SCREEN_H {
             for x in 0..SCREEN_W {
-                let r:u32 = (x as u32) % 255;
-                let ind = y * SCREEN_W + x;
-                dst[ind] = r << 16;
+                let color = if (x / 8 + y / 8) % 2 == 0 { 0 } else { 3 };
+                assert_eq!(frame[y * SCREEN_W + x], color, "pixel ({}, {})", x, y);
             }
         }
+
+        // Scrolling by one square inverts the checkerboard.
+        gpu.write_byte(SCX_ADDR, 8);
+        let frame = render_frame(&mut gpu);
+        assert_eq!([frame[0], frame[8], frame[8 * SCREEN_W]], [3, 0, 0]);
+    }
+
+    #[test]
+    fn test_bg_palette() {
+        let mut gpu = make_bg_gpu();
+        write_solid_tile(&mut gpu, 0, 1);
+
+        gpu.write_byte(BGP_ADDR, 0b0000_1000);
+        assert_eq!(gpu.read_byte(BGP_ADDR), 0b0000_1000);
+        assert_eq!(render_frame(&mut gpu)[0], 2);
+    }
+
+    #[test]
+    fn test_bg_tile_map_and_data() {
+        let mut gpu = make_bg_gpu();
+
+        // Tile 0 at 0x8000, tile 0 at 0x9000 (i.e. index 256), and tile
+        // -1 at 0x8FF0 (i.e. index 255).
+        write_solid_tile(&mut gpu, 0, 1);
+        write_solid_tile(&mut gpu, 256, 2);
+        write_solid_tile(&mut gpu, 255, 3);
+        gpu.write_byte(0x9c00, 0xff);
+
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE);
+        assert_eq!(render_frame(&mut gpu)[0], 1);
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE | LCDC_BG_ENABLE);
+        assert_eq!(render_frame(&mut gpu)[0], 2);
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE | LCDC_BG_MAP | LCDC_BG_ENABLE);
+        assert_eq!(render_frame(&mut gpu)[0], 3);
+    }
+
+    #[test]
+    fn test_bg_scroll() {
+        let mut gpu = make_bg_gpu();
+        for index in 0..4 {
+            write_solid_tile(&mut gpu, index, index as u8);
+        }
+
+        // First row of the map: tiles 0, 1, 2, 3; last row: tile 3.
+        for x in 0..4 {
+            gpu.write_byte(0x9800 + x, x as u8);
+        }
+        gpu.write_byte(0x9800 + 31 * 32, 3);
+
+        let frame = render_frame(&mut gpu);
+        assert_eq!([frame[0], frame[8], frame[16]], [0, 1, 2]);
+
+        // SCX=8 shifts the line by one tile.
+        gpu.write_byte(SCX_ADDR, 8);
+        assert_eq!(gpu.read_byte(SCX_ADDR), 8);
+        let frame = render_frame(&mut gpu);
+        assert_eq!([frame[0], frame[8], frame[16]], [1, 2, 3]);
+        gpu.write_byte(SCX_ADDR, 4);
+        let frame = render_frame(&mut gpu);
+        assert_eq!([frame[3], frame[4], frame[12]], [0, 1, 2]);
+
+        // The background wraps around.
+        gpu.write_byte(SCX_ADDR, 0);
+        gpu.write_byte(SCY_ADDR, 248);
+        assert_eq!(gpu.read_byte(SCY_ADDR), 248);
+        let frame = render_frame(&mut gpu);
+        assert_eq!([frame[0], frame[8 * SCREEN_W], frame[8 * SCREEN_W + 8]], [3, 0, 1]);
+    }
+
+    #[test]
+    fn test_bg_disabled() {
+        let mut gpu = make_bg_gpu();
+        write_solid_tile(&mut gpu, 0, 3);
+        assert_eq!(render_frame(&mut gpu)[0], 3);
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE | LCDC_TILE_DATA);
+        assert_eq!(render_frame(&mut gpu)[0], 0);
+    }
+
+    #[test]
+    fn test_sprite() {
+        let mut gpu = make_sprite_gpu();
+        write_solid_tile(&mut gpu, 1, 3);
+        write_sprite(&mut gpu, 0, 10, 20, 1, 0x00);
+
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[20 * SCREEN_W + 9..20 * SCREEN_W + 19], [0, 3, 3, 3, 3, 3, 3, 3, 3, 0]);
+        assert_eq!(frame[19 * SCREEN_W + 10], 0);
+        assert_eq!(frame[27 * SCREEN_W + 10], 3);
+        assert_eq!(frame[28 * SCREEN_W + 10], 0);
+
+        // Sprites can be partially off-screen.
+        write_sprite(&mut gpu, 0, -4, -4, 1, 0x00);
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[0..5], [3, 3, 3, 3, 0]);
+        assert_eq!(frame[4 * SCREEN_W], 0);
+
+        // Sprites are only drawn when enabled.
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE);
+        assert_eq!(render_frame(&mut gpu)[0], 0);
+    }
+
+    #[test]
+    fn test_sprite_flip() {
+        let mut gpu = make_sprite_gpu();
+
+        // Tile 1: only the top-left pixel is set (color 3).
+        write_tile_row(&mut gpu, 1, 0, &[3, 0, 0, 0, 0, 0, 0, 0]);
+
+        write_sprite(&mut gpu, 0, 0, 0, 1, 0x00);
+        write_sprite(&mut gpu, 1, 16, 0, 1, SPRITE_X_FLIP);
+        write_sprite(&mut gpu, 2, 32, 0, 1, SPRITE_Y_FLIP);
+        write_sprite(&mut gpu, 3, 48, 0, 1, SPRITE_X_FLIP | SPRITE_Y_FLIP);
+
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[0], 3);
+        assert_eq!(frame[16 + 7], 3);
+        assert_eq!(frame[7 * SCREEN_W + 32], 3);
+        assert_eq!(frame[7 * SCREEN_W + 48 + 7], 3);
+        assert_eq!(frame.iter().filter(|color| **color != 0).count(), 4);
+    }
+
+    #[test]
+    fn test_sprite_palettes() {
+        let mut gpu = make_sprite_gpu();
+        write_solid_tile(&mut gpu, 1, 1);
+        write_sprite(&mut gpu, 0, 0, 0, 1, 0x00);
+        write_sprite(&mut gpu, 1, 8, 0, 1, SPRITE_PALETTE);
+        gpu.write_byte(OBP0_ADDR, 0b0000_1000);
+        gpu.write_byte(OBP1_ADDR, 0b0000_1100);
+        assert_eq!(gpu.read_byte(OBP1_ADDR), 0b0000_1100);
+
+        let frame = render_frame(&mut gpu);
+        assert_eq!([frame[0], frame[8]], [2, 3]);
+    }
+
+    #[test]
+    fn test_sprite_transparency_and_priority() {
+        let mut gpu = make_sprite_gpu();
+
+        // Background: tile 2 (color 1) on the first tile of the map.
+        write_solid_tile(&mut gpu, 2, 1);
+        gpu.write_byte(0x9800, 2);
+
+        // Tile 1, first row: colors 0, 1, 2, 3, 0, 0, 0, 0.
+        write_tile_row(&mut gpu, 1, 0, &[0, 1, 2, 3, 0, 0, 0, 0]);
+
+        // Color 0 is transparent.
+        write_sprite(&mut gpu, 0, 8, 0, 1, 0x00);
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[8..12], [0, 1, 2, 3]);
+
+        // Behind the background, sprites only show over the color 0.
+        write_sprite(&mut gpu, 0, 6, 0, 1, SPRITE_BEHIND_BG);
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[6..10], [1, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_sprite_overlap() {
+        let mut gpu = make_sprite_gpu();
+        write_solid_tile(&mut gpu, 1, 1);
+        write_solid_tile(&mut gpu, 2, 2);
+
+        // The sprite with the smallest X wins, regardless of OAM order.
+        write_sprite(&mut gpu, 0, 4, 0, 1, 0x00);
+        write_sprite(&mut gpu, 1, 0, 0, 2, 0x00);
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[0..12], [2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1]);
+
+        // On a tie, the first sprite of the OAM wins.
+        write_sprite(&mut gpu, 0, 0, 0, 1, 0x00);
+        assert_eq!(render_frame(&mut gpu)[0], 1);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let mut gpu = make_sprite_gpu();
+        write_solid_tile(&mut gpu, 1, 3);
+        for index in 0..12 {
+            write_sprite(&mut gpu, index, 8 * index as isize, 0, 1, 0x00);
+        }
+        // Sprites off the scanline don't count towards the limit.
+        write_sprite(&mut gpu, 12, 0, 100, 1, 0x00);
+
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[79], 3);
+        assert_eq!(frame[80], 0);
+        assert_eq!(frame[100 * SCREEN_W], 3);
+    }
+
+    #[test]
+    fn test_sprite_8x16() {
+        let mut gpu = make_sprite_gpu();
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_SIZE
+            | LCDC_OBJ_ENABLE | LCDC_BG_ENABLE);
+        write_solid_tile(&mut gpu, 4, 1);
+        write_solid_tile(&mut gpu, 5, 2);
+
+        // The lower bit of the tile number is ignored.
+        write_sprite(&mut gpu, 0, 0, 0, 5, 0x00);
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[7 * SCREEN_W], 1);
+        assert_eq!(frame[8 * SCREEN_W], 2);
+        assert_eq!(frame[15 * SCREEN_W], 2);
+        assert_eq!(frame[16 * SCREEN_W], 0);
+
+        // Flipping vertically swaps the tiles.
+        write_sprite(&mut gpu, 0, 0, 0, 4, SPRITE_Y_FLIP);
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[0], 2);
+        assert_eq!(frame[15 * SCREEN_W], 1);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut gpu = make_window_gpu();
+        gpu.write_byte(WY_ADDR, 0);
+        gpu.write_byte(WX_ADDR, 7);
+        assert_eq!(gpu.read_byte(WX_ADDR), 7);
+        assert!(render_frame(&mut gpu).iter().all(|color| *color == 2));
+
+        gpu.write_byte(WY_ADDR, 10);
+        gpu.write_byte(WX_ADDR, 27);
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[9 * SCREEN_W + 20], 1);
+        assert_eq!(frame[10 * SCREEN_W + 19], 1);
+        assert_eq!(frame[10 * SCREEN_W + 20], 2);
+        assert_eq!(frame[SCREEN_H * SCREEN_W - 1], 2);
+
+        // The window is hidden past the right edge of the screen.
+        gpu.write_byte(WY_ADDR, 0);
+        gpu.write_byte(WX_ADDR, 166);
+        assert!(render_frame(&mut gpu).iter().all(|color| *color == 1));
+
+        // The window is only drawn with the background enabled.
+        gpu.write_byte(WX_ADDR, 7);
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE | LCDC_WIN_MAP | LCDC_WIN_ENABLE
+            | LCDC_TILE_DATA);
+        assert!(render_frame(&mut gpu).iter().all(|color| *color == 0));
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut gpu = make_window_gpu();
+        gpu.write_byte(WY_ADDR, 0);
+        gpu.write_byte(WX_ADDR, 7);
+
+        // The first window row is color 3; the others are color 2.
+        gpu.write_byte(0x9c00, 3);
+        write_solid_tile(&mut gpu, 3, 3);
+
+        // Hide the window on the lines 0-19 by moving it off-screen: the
+        // window then starts from its first row on the line 20.
+        for _ in 0..20 {
+            gpu.write_byte(WX_ADDR, 166);
+            gpu.step(LINE_NCYCLES);
+        }
+        gpu.write_byte(WX_ADDR, 7);
+        let frame = render_frame(&mut gpu);
+        assert_eq!(frame[19 * SCREEN_W], 1);
+        assert_eq!(frame[20 * SCREEN_W], 3);
+        assert_eq!(frame[27 * SCREEN_W], 3);
+        assert_eq!(frame[28 * SCREEN_W], 2);
+    }
+
+    #[test]
+    fn test_lcd_disabled_blanks_screen() {
+        let mut gpu = Gpu::new();
+        let mut screen = vec![0; SCREEN_W * SCREEN_H];
+        gpu.frame_front[0] = 3;
+
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE);
+        gpu.copy_screen(&mut screen[..]);
+        assert_eq!(screen[0], SHADES[3]);
+
+        gpu.write_byte(LCDC_ADDR, 0x00);
+        gpu.copy_screen(&mut screen[..]);
+        assert_eq!(screen[0], SHADES[0]);
+    }
+
+    #[test]
+    fn test_double_buffering() {
+        let mut gpu = Gpu::new();
+        let mut screen = vec![0; SCREEN_W * SCREEN_H];
+        gpu.write_byte(LCDC_ADDR, LCDC_LCD_ENABLE);
+
+        // Start rendering a frame; the front buffer still holds the
+        // previous (blank) frame.
+        gpu.step(FRAME_NCYCLES / 2);
+        gpu.frame_back[0] = 3;
+        assert_eq!(gpu.framebuffer()[0], 0);
+        gpu.copy_screen(&mut screen[..]);
+        assert_eq!(screen[0], SHADES[0]);
+
+        // Complete the frame; it gets swapped to the front buffer.
+        gpu.step(FRAME_NCYCLES / 2);
+        assert_eq!(gpu.framebuffer()[0], 3);
+        gpu.copy_screen(&mut screen[..]);
+        assert_eq!(screen[0], SHADES[3]);
     }
 }
@@ -1,2 +1,5 @@
 pub mod gpu;
 pub use self::gpu::Gpu;
+
+#[cfg(test)]
+pub mod fixtures;